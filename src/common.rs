@@ -1,7 +1,16 @@
 use serde::{Serialize, Deserialize};
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::index::TagIndex;
+use crate::journal::JournalRecord;
+use crate::migrate::CURRENT_VERSION;
+use crate::path_cache::PathCache;
 
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub enum TagType {
@@ -22,7 +31,14 @@ pub struct EntsTag {
     pub children: Vec<String>, //this is inodes now
     pub ancestry: Vec<String>, //this is inodes now
     pub show: Option<bool>,
-    pub files: Option<Vec<String>>, //this is inodes now
+    pub files: Option<Vec<String>>, //this is device:inode file ids now
+    // When and how each file in `files` was assigned, by file id
+    // Assignments made before this was recorded have no entry
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub assigned: HashMap<String, Assignment>,
+    // Comments written with the tag in the taxonomy file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
     
     // Fields used during parsing, skipped during serialization
     #[serde(skip)]
@@ -40,11 +56,38 @@ impl EntsTag {
             ancestry,
             show: Some(true),
             files: Some(Vec::new()),
+            assigned: HashMap::new(),
+            description: None,
             child_tags: Vec::new(),
             alias: None,
         }
     }
     
+    /// The tag's name qualified by its ancestors, e.g. `invoices/2023`
+    /// Leaf names may repeat across branches, but a path names exactly one tag
+    pub fn path(&self) -> String {
+        self.ancestor_paths().last()
+            .map_or_else(|| self.name.clone(), |parent| format!("{}/{}", parent, self.name))
+    }
+
+    /// The path of every ancestor, outermost first
+    pub fn ancestor_paths(&self) -> Vec<String> {
+        self.ancestry.iter()
+            .scan(String::new(), |path, ancestor| {
+                if !path.is_empty() {
+                    path.push('/');
+                }
+                path.push_str(ancestor);
+                Some(path.clone())
+            })
+            .collect()
+    }
+
+    /// The path of the child called `child`
+    pub fn child_path(&self, child: &str) -> String {
+        format!("{}/{}", self.path(), child)
+    }
+
     // Call this before serialization to convert child_tags to children names
     pub fn finalize(&mut self) {
         // Extract children names from child_tags
@@ -59,38 +102,257 @@ impl EntsTag {
     }
 }
 
+/// Metadata about one file being assigned one tag
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Assignment {
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    // Whether the assignment was made with --force, displacing other tags
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forced: bool,
+}
+
+impl Assignment {
+    pub fn now(forced: bool) -> Self {
+        Assignment { timestamp: unix_timestamp(), forced }
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn unix_timestamp() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Format a Unix timestamp as a UTC date and time, e.g. `2024-03-09 14:05`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let minutes_of_day = timestamp % 86400 / 60;
+
+    // Convert days since 1970-01-01 to a civil date
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, minutes_of_day / 60, minutes_of_day % 60)
+}
+
+/// Identity of a file on disk
+/// Inode numbers are only unique within one device, so both are needed to tell files apart
+/// Written as `device:inode` in `EntsTag.files`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub device_id: u64,
+    pub inode: u64,
+}
+
+impl FileId {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        FileId {
+            device_id: metadata.dev(),
+            inode: metadata.ino(),
+        }
+    }
+}
+
+impl fmt::Display for FileId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.device_id, self.inode)
+    }
+}
+
+impl FromStr for FileId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (device_id, inode) = s.split_once(':')
+            .ok_or_else(|| format!("invalid file id '{}'", s))?;
+        Ok(FileId {
+            device_id: device_id.parse().map_err(|_| format!("invalid device in file id '{}'", s))?,
+            inode: inode.parse().map_err(|_| format!("invalid inode in file id '{}'", s))?,
+        })
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct FileData {
     pub last_known_name: String,
     pub file_inode: u64,
     pub parent_dir_inode: u64,
-    // pub sha1_hash: [u8; 40],
-    // pub fuzzy_hash: [u8; 70]
+    // st_dev of the filesystem holding the file; 0 in databases written before it was recorded
+    #[serde(default)]
+    pub device_id: u64,
+    // Hex-encoded SHA-1 of the contents when the file was first tracked
+    // Lets a file be recognised again after a copy or restore gives it a new inode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha1_hash: Option<String>,
+    // Similarity digest of the contents, see fuzzy.rs
+    // Lets a file be proposed as a match even after it was edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fuzzy_hash: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TagsFile {
+    // Layout version, see migrate.rs; databases written before it was recorded read as 0
+    #[serde(default)]
+    pub version: u32,
     pub files: Vec<FileData>,
     pub aliases: HashMap<String, String>,
     pub tags: Vec<EntsTag>,
+
+    // Absolute directories a shared database covers; empty for a database that
+    // covers only the tree it sits in. Files under registered roots are stored by absolute path
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roots: Vec<String>,
+
+    // Set for the user-level database, which has no tree of its own to search without roots
+    #[serde(skip)]
+    pub global: bool,
+
+    // Set when the in-memory copy has changes that should be saved, skipped during serialization
+    #[serde(skip)]
+    pub dirty: bool,
+
+    // Changes made since loading, appended to the journal as one transaction when saved
+    #[serde(skip)]
+    pub journal: Vec<JournalRecord>,
+
+    // Lookup tables over `tags` and `files`, see index.rs
+    #[serde(skip)]
+    pub index: TagIndex,
+
+    // Listing of the searched trees used for name and inode lookups, see path_cache.rs
+    #[serde(skip)]
+    pub paths: PathCache,
+}
+
+impl Default for TagsFile {
+    // A new database is always written in the current layout
+    fn default() -> Self {
+        TagsFile {
+            version: CURRENT_VERSION,
+            files: Vec::new(),
+            aliases: HashMap::new(),
+            tags: Vec::new(),
+            roots: Vec::new(),
+            global: false,
+            dirty: false,
+            journal: Vec::new(),
+            index: TagIndex::default(),
+            paths: PathCache::default(),
+        }
+    }
 }
 
-pub fn read_tags_from_json() -> Result<TagsFile, Box<dyn Error>> {
-    match fs::read_to_string("tags.json") {
-        Ok(json_content) => {
-            let tags_file: TagsFile = serde_json::from_str(&json_content)?;
-            Ok(tags_file)
-        },
-        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-            println!("Error: tags.json not found. Run 'prlents process tags.ents' to create it.");
-            Ok(TagsFile::default())  
-        },
-        Err(e) => Err(e.into())
+impl FileData {
+    pub fn id(&self) -> FileId {
+        FileId {
+            device_id: self.device_id,
+            inode: self.file_inode,
+        }
     }
 }
 
-pub fn save_tags_to_json(tags_file: &TagsFile) -> Result<(), Box<dyn Error>> {
-    let json_content = serde_json::to_string_pretty(tags_file)?;
-    fs::write("tags.json", json_content)?;
-    Ok(())
+impl TagsFile {
+    /// Directories to walk when looking for files by name, inode or hash
+    /// The global database only searches its registered roots, since walking wherever the user
+    /// happens to be would store paths relative to that directory
+    pub fn search_roots(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        if !self.roots.is_empty() {
+            Ok(self.roots.clone())
+        } else if self.global {
+            Err("the global database has no roots to search; register one with prlents -g root add <dir>".into())
+        } else {
+            Ok(vec![".".to_string()])
+        }
+    }
+
+    /// Move every tag assignment from `old_id` to `new_id` and update the file record
+    /// Used when a tracked file is found again under a different inode; marks the database dirty
+    pub fn rekey_file(&mut self, old_id: FileId, new_id: FileId) {
+        self.dirty = true;
+        let old_id_str = old_id.to_string();
+        let new_id_str = new_id.to_string();
+
+        for tag in &mut self.tags {
+            if let Some(files) = &mut tag.files {
+                if let Some(pos) = files.iter().position(|f| *f == old_id_str) {
+                    if files.contains(&new_id_str) {
+                        files.remove(pos);
+                    } else {
+                        files[pos] = new_id_str.clone();
+                    }
+                }
+            }
+            if let Some(assignment) = tag.assigned.remove(&old_id_str) {
+                tag.assigned.entry(new_id_str.clone()).or_insert(assignment);
+            }
+        }
+
+        // Drop any stale record already using the new id before taking it over
+        if old_id != new_id {
+            if let Some(stale_position) = self.file_position(new_id) {
+                self.files.remove(stale_position);
+                self.index.remove_file(stale_position);
+            }
+        }
+        if let Some(position) = self.file_position(old_id) {
+            self.files[position].file_inode = new_id.inode;
+            self.files[position].device_id = new_id.device_id;
+        }
+
+        // Only this id changed, so the index is updated in place rather than rebuilt
+        self.index.rekey_file(old_id, new_id);
+        if old_id != new_id {
+            self.journal.push(JournalRecord::Rekey { from: old_id_str, to: new_id_str });
+        }
+    }
+
+    /// Upgrade a database written before device ids were recorded
+    /// Each file record takes the device of its last known path, falling back to the
+    /// current directory's device, and bare inodes in `EntsTag.files` become `device:inode`
+    pub fn migrate_device_ids(&mut self) -> Result<(), Box<dyn Error>> {
+        let needs_migration = self.files.iter().any(|f| f.device_id == 0)
+            || self.tags.iter()
+                .filter_map(|t| t.files.as_ref())
+                .any(|files| files.iter().any(|f| !f.contains(':')));
+
+        if !needs_migration {
+            return Ok(());
+        }
+
+        let fallback_device_id = fs::metadata(".")?.dev();
+
+        for file_data in &mut self.files {
+            if file_data.device_id == 0 {
+                file_data.device_id = match fs::metadata(&file_data.last_known_name) {
+                    Ok(metadata) if metadata.ino() == file_data.file_inode => metadata.dev(),
+                    _ => fallback_device_id,
+                };
+            }
+        }
+
+        let device_by_inode: HashMap<u64, u64> = self.files.iter()
+            .map(|f| (f.file_inode, f.device_id))
+            .collect();
+
+        for tag in &mut self.tags {
+            if let Some(files) = &mut tag.files {
+                for entry in files.iter_mut() {
+                    if let Ok(inode) = entry.parse::<u64>() {
+                        let device_id = device_by_inode.get(&inode).copied().unwrap_or(fallback_device_id);
+                        *entry = FileId { device_id, inode }.to_string();
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
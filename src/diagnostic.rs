@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fmt;
+
+/// A problem found in a taxonomy file, with enough context to show the user where it is
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    // 1-based line and column; the column counts characters, not bytes
+    pub line: usize,
+    pub column: usize,
+    // Number of characters to underline, at least one
+    pub length: usize,
+    // The offending line as written
+    pub source: String,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Point at the `length` bytes of `source` starting at the byte offset `start`
+    /// Both are converted to characters so multibyte text is underlined correctly
+    pub fn new(file: &str, line: usize, source: &str, start: usize, length: usize, message: String) -> Self {
+        let length = source.get(start..start + length).map_or(length, |span| span.chars().count());
+        Diagnostic {
+            file: file.to_string(),
+            line,
+            column: source[..start].chars().count() + 1,
+            length: length.max(1),
+            source: source.to_string(),
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    /// Render in the style of rustc:
+    /// ```text
+    /// error: alias is missing its closing parenthesis
+    ///  --> tags.ents:3:14
+    ///   |
+    /// 3 |     - new york (ny
+    ///   |                ^^^
+    /// ```
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+
+        // Keep tabs so the carets line up under the same characters in a terminal
+        let padding: String = self.source.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        writeln!(f, "error: {}", self.message)?;
+        writeln!(f, "{}--> {}:{}:{}", gutter, self.file, self.line, self.column)?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", self.line, self.source)?;
+        write!(f, "{} | {}{}", gutter, padding, "^".repeat(self.length))
+    }
+}
+
+/// Every problem found in one pass over a file, reported together
+#[derive(Debug)]
+pub struct Diagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for diagnostic in &self.0 {
+            writeln!(f, "{}\n", diagnostic)?;
+        }
+        let file = self.0.first().map_or("", |d| d.file.as_str());
+        write!(f, "found {} error{} in {}", self.0.len(), if self.0.len() == 1 { "" } else { "s" }, file)
+    }
+}
+
+impl Error for Diagnostics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_underlines_the_span() {
+        let diagnostic = Diagnostic::new("tags.ents", 3, "\t- new york (ny", 12, 3, "alias is missing its closing parenthesis".to_string());
+        assert_eq!(diagnostic.column, 13);
+        assert_eq!(diagnostic.to_string(), concat!(
+            "error: alias is missing its closing parenthesis\n",
+            " --> tags.ents:3:13\n",
+            "  |\n",
+            "3 | \t- new york (ny\n",
+            "  | \t           ^^^",
+        ));
+    }
+
+    #[test]
+    fn test_multibyte_span_counts_characters() {
+        let source = "- café (cé)";
+        let start = source.find("café").unwrap();
+        let diagnostic = Diagnostic::new("tags.ents", 2, source, start, "café".len(), "duplicate tag 'café'".to_string());
+        assert_eq!((diagnostic.column, diagnostic.length), (3, 4));
+        assert!(diagnostic.to_string().ends_with("\n  |   ^^^^"));
+
+        let start = source.find("cé").unwrap();
+        let diagnostic = Diagnostic::new("tags.ents", 2, source, start, "cé".len(), "alias reused".to_string());
+        assert_eq!((diagnostic.column, diagnostic.length), (9, 2));
+    }
+}
@@ -0,0 +1,244 @@
+use nom::{
+    IResult,
+    branch::alt,
+    bytes::complete::{tag_no_case, take_while, take_while1, is_not},
+    character::complete::{char, digit1, one_of},
+    combinator::{map, map_res, not, peek, eof},
+    multi::many0,
+    sequence::{preceded, delimited, terminated},
+};
+use std::collections::HashSet;
+use std::error::Error;
+
+use crate::common::{TagsFile, unix_timestamp};
+use crate::relationship::{filter_inodes, all_tagged_inodes, assigned_inodes, resolve_inodes};
+
+/// A boolean query over tags
+/// Precedence from loosest to tightest binding: `or`, `and`/`except`, `not`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    Tag(String),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Except(Box<Expr>, Box<Expr>),
+    // Files given any visible tag within the last N seconds, or longer ago than that
+    AddedWithin(u64),
+    AddedBefore(u64),
+}
+
+impl Expr {
+    /// Evaluate the expression to the set of file inodes it matches
+    /// `not` is taken relative to every file that carries at least one visible tag
+    pub fn evaluate(&self, tags_file: &TagsFile, explicit: bool) -> Result<HashSet<String>, Box<dyn Error>> {
+        match self {
+            Expr::Tag(name) => filter_inodes(tags_file, std::slice::from_ref(name), explicit),
+            Expr::Not(inner) => {
+                let excluded = inner.evaluate(tags_file, explicit)?;
+                Ok(all_tagged_inodes(tags_file).difference(&excluded).cloned().collect())
+            },
+            Expr::And(left, right) => {
+                let left_set = left.evaluate(tags_file, explicit)?;
+                let right_set = right.evaluate(tags_file, explicit)?;
+                Ok(left_set.intersection(&right_set).cloned().collect())
+            },
+            Expr::Or(left, right) => {
+                let mut left_set = left.evaluate(tags_file, explicit)?;
+                left_set.extend(right.evaluate(tags_file, explicit)?);
+                Ok(left_set)
+            },
+            Expr::Except(left, right) => {
+                // The excluded side always expands children, matching `difference_inodes`
+                let left_set = left.evaluate(tags_file, explicit)?;
+                let right_set = right.evaluate(tags_file, false)?;
+                Ok(left_set.difference(&right_set).cloned().collect())
+            },
+            Expr::AddedWithin(seconds) => {
+                let cutoff = unix_timestamp().saturating_sub(*seconds);
+                Ok(assigned_inodes(tags_file, |assignment| assignment.timestamp >= cutoff))
+            },
+            Expr::AddedBefore(seconds) => {
+                let cutoff = unix_timestamp().saturating_sub(*seconds);
+                Ok(assigned_inodes(tags_file, |assignment| assignment.timestamp < cutoff))
+            },
+        }
+    }
+}
+
+/// Parse zero or more whitespace characters between tokens
+fn parse_whitespace(input: &str) -> IResult<&str, &str> {
+    take_while(|c: char| c.is_whitespace())(input)
+}
+
+/// Characters that may appear in an unquoted tag name
+fn is_bare_tag_char(c: char) -> bool {
+    !c.is_whitespace() && c != '(' && c != ')' && c != '"'
+}
+
+/// Parse a keyword such as `and`, case-insensitively
+/// The keyword must not run straight into a tag name, so `android` is a tag, not `and` + `roid`
+fn parse_keyword<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    terminated(
+        preceded(parse_whitespace, tag_no_case(keyword)),
+        not(peek(take_while1(is_bare_tag_char))),
+    )
+}
+
+/// Parse a tag name, either bare (`photos`) or double quoted (`"new york"`)
+/// Quoting is needed for names containing spaces or parentheses, or that collide with a keyword
+fn parse_query_tag(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = parse_whitespace(input)?;
+
+    // Bare words that are keywords are never tag names
+    if let Ok((_, word)) = take_while1::<_, _, nom::error::Error<&str>>(is_bare_tag_char)(input) {
+        if ["and", "or", "not", "except"].iter().any(|k| word.eq_ignore_ascii_case(k)) {
+            return Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)));
+        }
+    }
+
+    alt((
+        map(delimited(char('"'), is_not("\""), char('"')), |s: &str| Expr::Tag(s.to_string())),
+        map(take_while1(is_bare_tag_char), |s: &str| Expr::Tag(s.to_string())),
+    ))(input)
+}
+
+/// Parse an age predicate: `added<7d` matches files tagged within the last 7 days, `added>7d` before that
+/// Ages are given in hours (`h`), days (`d`) or weeks (`w`); a tag with such a name must be quoted
+fn parse_added(input: &str) -> IResult<&str, Expr> {
+    let (input, _) = preceded(parse_whitespace, tag_no_case("added"))(input)?;
+    let (input, within) = alt((map(char('<'), |_| true), map(char('>'), |_| false)))(input)?;
+    let (input, amount) = map_res(digit1, str::parse::<u64>)(input)?;
+    let (input, unit) = terminated(one_of("hdw"), not(peek(take_while1(is_bare_tag_char))))(input)?;
+
+    let seconds = amount.saturating_mul(match unit {
+        'h' => 3600,
+        'd' => 86400,
+        _ => 7 * 86400,
+    });
+    Ok((input, if within { Expr::AddedWithin(seconds) } else { Expr::AddedBefore(seconds) }))
+}
+
+/// Parse a parenthesised sub-expression, an age predicate or a single tag
+fn parse_primary(input: &str) -> IResult<&str, Expr> {
+    alt((
+        delimited(
+            preceded(parse_whitespace, char('(')),
+            parse_or,
+            preceded(parse_whitespace, char(')')),
+        ),
+        parse_added,
+        parse_query_tag,
+    ))(input)
+}
+
+/// Parse any number of `not` prefixes followed by a primary expression
+fn parse_not(input: &str) -> IResult<&str, Expr> {
+    alt((
+        map(preceded(parse_keyword("not"), parse_not), |e| Expr::Not(Box::new(e))),
+        parse_primary,
+    ))(input)
+}
+
+/// Parse a left-associative chain of `and` and `except` operations
+fn parse_and(input: &str) -> IResult<&str, Expr> {
+    let (input, first) = parse_not(input)?;
+    let (input, rest) = many0(alt((
+        map(preceded(parse_keyword("and"), parse_not), |e| (true, e)),
+        map(preceded(parse_keyword("except"), parse_not), |e| (false, e)),
+    )))(input)?;
+
+    let expr = rest.into_iter()
+        .fold(first, |acc, (is_and, e)| if is_and {
+            Expr::And(Box::new(acc), Box::new(e))
+        } else {
+            Expr::Except(Box::new(acc), Box::new(e))
+        });
+    Ok((input, expr))
+}
+
+/// Parse a left-associative chain of `or` operations
+fn parse_or(input: &str) -> IResult<&str, Expr> {
+    let (input, first) = parse_and(input)?;
+    let (input, rest) = many0(preceded(parse_keyword("or"), parse_and))(input)?;
+
+    let expr = rest.into_iter()
+        .fold(first, |acc, e| Expr::Or(Box::new(acc), Box::new(e)));
+    Ok((input, expr))
+}
+
+/// Parse a complete query string such as `(photos or scans) and 2023 and not private`
+pub fn parse_expression(input: &str) -> Result<Expr, Box<dyn Error>> {
+    match terminated(parse_or, preceded(parse_whitespace, eof))(input) {
+        Ok((_, expr)) => Ok(expr),
+        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
+            let position = input.len() - e.input.len();
+            Err(format!("invalid query at column {}: {:?}", position + 1, e.input).into())
+        },
+        Err(e) => Err(format!("invalid query: {:?}", e).into()),
+    }
+}
+
+/// Parse and evaluate a query, returning the matching filenames
+pub fn query_command(tags_file: &mut TagsFile, query: &str, explicit: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let expr = parse_expression(query)?;
+    let inodes = expr.evaluate(tags_file, explicit)?;
+    resolve_inodes(tags_file, &inodes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> Box<Expr> {
+        Box::new(Expr::Tag(name.to_string()))
+    }
+
+    /// Test that `and` binds tighter than `or` and `not` tighter than both
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse_expression("a or b and not c").unwrap(),
+            Expr::Or(tag("a"), Box::new(Expr::And(tag("b"), Box::new(Expr::Not(tag("c"))))))
+        );
+    }
+
+    /// Test parentheses, quoting and keyword-prefixed tag names
+    #[test]
+    fn test_parentheses_and_quotes() {
+        assert_eq!(
+            parse_expression("(photos OR scans) and \"new york\"").unwrap(),
+            Expr::And(Box::new(Expr::Or(tag("photos"), tag("scans"))), tag("new york"))
+        );
+        assert_eq!(parse_expression("android").unwrap(), Expr::Tag("android".to_string()));
+    }
+
+    /// Test that `except` chains left to right alongside `and`
+    #[test]
+    fn test_except() {
+        assert_eq!(
+            parse_expression("invoices except paid and 2023").unwrap(),
+            Expr::And(Box::new(Expr::Except(tag("invoices"), tag("paid"))), tag("2023"))
+        );
+    }
+
+    /// Test age predicates and that they leave similarly named tags alone
+    #[test]
+    fn test_added_predicates() {
+        assert_eq!(
+            parse_expression("photos and added<7d").unwrap(),
+            Expr::And(tag("photos"), Box::new(Expr::AddedWithin(7 * 86400)))
+        );
+        assert_eq!(parse_expression("added>2w").unwrap(), Expr::AddedBefore(14 * 86400));
+        assert_eq!(parse_expression("added").unwrap(), Expr::Tag("added".to_string()));
+        assert_eq!(parse_expression("\"added<7d\"").unwrap(), Expr::Tag("added<7d".to_string()));
+        assert_eq!(parse_expression("added<7days").unwrap(), Expr::Tag("added<7days".to_string()));
+    }
+
+    /// Test that malformed queries are rejected
+    #[test]
+    fn test_invalid_queries() {
+        assert!(parse_expression("photos and").is_err());
+        assert!(parse_expression("(photos or scans").is_err());
+        assert!(parse_expression("").is_err());
+    }
+}
@@ -0,0 +1,238 @@
+// Context-triggered piecewise hashing in the style of ssdeep
+// A digest looks like `blocksize:signature:double_blocksize_signature`, and two digests
+// can be compared for a 0-100 similarity score even after the file has been edited
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+const ROLLING_WINDOW: usize = 7;
+const MIN_BLOCKSIZE: u32 = 3;
+const SPAMSUM_LENGTH: usize = 64;
+const HASH_PRIME: u32 = 0x0100_0193;
+const HASH_INIT: u32 = 0x2802_1967;
+const B64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Rolling hash over the last `ROLLING_WINDOW` bytes, used to pick block boundaries
+struct RollingHash {
+    window: [u8; ROLLING_WINDOW],
+    h1: u32,
+    h2: u32,
+    h3: u32,
+    n: usize,
+}
+
+impl RollingHash {
+    fn new() -> Self {
+        RollingHash { window: [0; ROLLING_WINDOW], h1: 0, h2: 0, h3: 0, n: 0 }
+    }
+
+    fn update(&mut self, c: u8) -> u32 {
+        let c32 = c as u32;
+        self.h2 = self.h2.wrapping_sub(self.h1).wrapping_add((ROLLING_WINDOW as u32).wrapping_mul(c32));
+        self.h1 = self.h1.wrapping_add(c32).wrapping_sub(self.window[self.n % ROLLING_WINDOW] as u32);
+        self.window[self.n % ROLLING_WINDOW] = c;
+        self.n += 1;
+        self.h3 = (self.h3 << 5) ^ c32;
+        self.sum()
+    }
+
+    fn sum(&self) -> u32 {
+        self.h1.wrapping_add(self.h2).wrapping_add(self.h3)
+    }
+}
+
+/// FNV-style hash of the bytes in the current block
+fn sum_hash(c: u8, h: u32) -> u32 {
+    h.wrapping_mul(HASH_PRIME) ^ (c as u32)
+}
+
+/// Both signatures for a single block size, built up one byte at a time
+struct Signatures {
+    block_size: u32,
+    roll: RollingHash,
+    h1: u32,
+    h2: u32,
+    sig1: String,
+    sig2: String,
+}
+
+impl Signatures {
+    fn new(block_size: u32) -> Self {
+        Signatures { block_size, roll: RollingHash::new(), h1: HASH_INIT, h2: HASH_INIT, sig1: String::new(), sig2: String::new() }
+    }
+
+    fn update(&mut self, c: u8) {
+        let block_size = self.block_size;
+        self.h1 = sum_hash(c, self.h1);
+        self.h2 = sum_hash(c, self.h2);
+        let rolling = self.roll.update(c);
+
+        if rolling % block_size == block_size - 1 {
+            // Once a signature is full, the last character absorbs the rest of the input
+            if self.sig1.len() < SPAMSUM_LENGTH - 1 {
+                self.sig1.push(B64[(self.h1 % 64) as usize] as char);
+                self.h1 = HASH_INIT;
+            }
+            if rolling % (block_size * 2) == block_size * 2 - 1 && self.sig2.len() < SPAMSUM_LENGTH / 2 - 1 {
+                self.sig2.push(B64[(self.h2 % 64) as usize] as char);
+                self.h2 = HASH_INIT;
+            }
+        }
+    }
+
+    fn finish(mut self) -> (String, String) {
+        if self.roll.sum() != 0 {
+            self.sig1.push(B64[(self.h1 % 64) as usize] as char);
+            self.sig2.push(B64[(self.h2 % 64) as usize] as char);
+        }
+        (self.sig1, self.sig2)
+    }
+}
+
+/// Compute the similarity digest of everything in `input`, reading it in chunks rather than all at once
+/// The input is read again from the start whenever the block size has to be halved
+pub fn fuzzy_hash<R: Read + Seek>(input: &mut R) -> io::Result<String> {
+    let length = input.seek(SeekFrom::End(0))?;
+    let mut block_size = MIN_BLOCKSIZE;
+    while (block_size as u64) * (SPAMSUM_LENGTH as u64) < length {
+        block_size *= 2;
+    }
+
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        input.seek(SeekFrom::Start(0))?;
+        let mut signatures = Signatures::new(block_size);
+        loop {
+            let read = match input.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(read) => read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            buffer[..read].iter().for_each(|&c| signatures.update(c));
+        }
+
+        let (sig1, sig2) = signatures.finish();
+        // Too few boundaries means the block size was too coarse for this input
+        if block_size > MIN_BLOCKSIZE && sig1.len() < SPAMSUM_LENGTH / 2 {
+            block_size /= 2;
+            continue;
+        }
+        return Ok(format!("{}:{}:{}", block_size, sig1, sig2));
+    }
+}
+
+/// Collapse runs of more than three identical characters, which carry little information
+fn eliminate_sequences(s: &str) -> Vec<u8> {
+    let mut result: Vec<u8> = Vec::with_capacity(s.len());
+    for &c in s.as_bytes() {
+        let len = result.len();
+        if len >= 3 && result[len - 1] == c && result[len - 2] == c && result[len - 3] == c {
+            continue;
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Whether the two signatures share a run of at least `ROLLING_WINDOW` characters
+fn has_common_substring(s1: &[u8], s2: &[u8]) -> bool {
+    if s1.len() < ROLLING_WINDOW || s2.len() < ROLLING_WINDOW {
+        return false;
+    }
+    s1.windows(ROLLING_WINDOW).any(|w| s2.windows(ROLLING_WINDOW).any(|v| v == w))
+}
+
+/// Edit distance where insertions and deletions cost 1 and substitutions cost 2
+fn edit_distance(s1: &[u8], s2: &[u8]) -> usize {
+    let mut previous: Vec<usize> = (0..=s2.len()).collect();
+    let mut current = vec![0; s2.len() + 1];
+
+    for (i, &a) in s1.iter().enumerate() {
+        current[0] = i + 1;
+        for (j, &b) in s2.iter().enumerate() {
+            let substitution = previous[j] + if a == b { 0 } else { 2 };
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+
+    previous[s2.len()]
+}
+
+/// Score two signatures computed with the same block size
+fn score_strings(s1: &[u8], s2: &[u8], block_size: u32) -> u32 {
+    if !has_common_substring(s1, s2) {
+        return 0;
+    }
+
+    let distance = edit_distance(s1, s2) * SPAMSUM_LENGTH / (s1.len() + s2.len());
+    let distance = (100 * distance / SPAMSUM_LENGTH) as u32;
+    if distance >= 100 {
+        return 0;
+    }
+    let score = 100 - distance;
+
+    // Small block sizes on short inputs would otherwise exaggerate the match
+    if block_size >= (99 + ROLLING_WINDOW as u32) / ROLLING_WINDOW as u32 * MIN_BLOCKSIZE {
+        return score;
+    }
+    let cap = block_size / MIN_BLOCKSIZE * s1.len().min(s2.len()) as u32;
+    score.min(cap)
+}
+
+/// Compare two digests produced by `fuzzy_hash`, returning a similarity from 0 to 100
+pub fn fuzzy_compare(digest1: &str, digest2: &str) -> u32 {
+    fn split(digest: &str) -> Option<(u32, &str, &str)> {
+        let mut parts = digest.splitn(3, ':');
+        let block_size = parts.next()?.parse().ok()?;
+        Some((block_size, parts.next()?, parts.next()?))
+    }
+
+    let (Some((b1, s1a, s1b)), Some((b2, s2a, s2b))) = (split(digest1), split(digest2)) else {
+        return 0;
+    };
+
+    if digest1 == digest2 {
+        return 100;
+    }
+
+    let (s1a, s1b) = (eliminate_sequences(s1a), eliminate_sequences(s1b));
+    let (s2a, s2b) = (eliminate_sequences(s2a), eliminate_sequences(s2b));
+
+    if b1 == b2 {
+        score_strings(&s1a, &s2a, b1).max(score_strings(&s1b, &s2b, b1 * 2))
+    } else if b1 == b2 * 2 {
+        score_strings(&s1a, &s2b, b1)
+    } else if b2 == b1 * 2 {
+        score_strings(&s1b, &s2a, b2)
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn digest(text: &str) -> String {
+        fuzzy_hash(&mut Cursor::new(text.as_bytes())).unwrap()
+    }
+
+    fn sample_text(seed: u32, lines: u32) -> String {
+        (0..lines).map(|i| format!("line {} of document {} with some filler text\n", i * 7919 % 1000, seed)).collect()
+    }
+
+    /// Test that edited copies score high and unrelated files score low
+    #[test]
+    fn test_fuzzy_compare() {
+        let original = sample_text(1, 400);
+        let mut edited = original.clone();
+        edited.insert_str(original.len() / 2, "a paragraph inserted in the middle of the file\n");
+
+        let original_digest = digest(&original);
+        assert_eq!(fuzzy_compare(&original_digest, &original_digest), 100);
+        assert!(fuzzy_compare(&original_digest, &digest(&edited)) > 50);
+        assert_eq!(fuzzy_compare(&original_digest, &digest(&sample_text(2, 400))), 0);
+    }
+}
@@ -1,40 +1,91 @@
 use std::fs;
+use std::io;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::error::Error;
 use std::os::unix::fs::MetadataExt;
-use jwalk::WalkDir;
-use crate::common::{TagsFile, FileData};
+use sha1::{Digest, Sha1};
+use crate::common::{TagsFile, FileData, FileId};
+use crate::fuzzy::fuzzy_hash;
+use crate::path_cache::PathCache;
 
 struct FileLocation {
     path: PathBuf,
-    file_inode: u64,
+    file_id: FileId,
     parent_dir_inode: u64,
 }
 
-pub fn handle_file(file_path: &str, jf: &mut TagsFile) -> Result<u64, Box<dyn Error>> {
+pub fn handle_file(file_path: &str, jf: &mut TagsFile) -> Result<FileId, Box<dyn Error>> {
     if jf.files.is_empty() {
         jf.files = Vec::new();
     }
     
     if let Some(existing_file) = jf.files.iter().find(|file| file.last_known_name == file_path) { 
-        return Ok(existing_file.file_inode);
+        let existing_id = existing_file.id();
+        return Ok(follow_replaced_file(jf, existing_id, file_path).unwrap_or(existing_id));
     }
     
-    match find_file_with_inodes(file_path)? {
+    // Paths arrive relative to the workspace root, or absolute when the database spans several roots
+    let location = if Path::new(file_path).exists() {
+        Some(locate(Path::new(file_path))?)
+    } else {
+        find_file_by_name(file_path, &jf.search_roots()?, &mut jf.paths)?
+    };
+
+    match location {
         Some(location) => {
-            let file_inode = location.file_inode;    
-            if let Some(position) = jf.files.iter().position(|file| file.file_inode == location.file_inode) {
-                jf.files[position].last_known_name = location.path.to_string_lossy().to_string();
-                jf.files[position].parent_dir_inode = location.parent_dir_inode;
+            let file_id = location.file_id;
+            let last_known_name = location.path.to_string_lossy().to_string();
+
+            if let Some(position) = jf.file_position(file_id) {
+                if jf.files[position].sha1_hash.is_none() {
+                    jf.files[position].sha1_hash = hash_file(&location.path).ok();
+                }
+                if jf.files[position].fuzzy_hash.is_none() {
+                    jf.files[position].fuzzy_hash = fuzzy_hash_file(&location.path).ok();
+                }
+                if jf.files[position].last_known_name != last_known_name {
+                    jf.files[position].last_known_name = last_known_name;
+                    jf.files[position].parent_dir_inode = location.parent_dir_inode;
+                    jf.dirty = true;
+                }
+                return Ok(file_id);
+            }
+
+            let sha1_hash = hash_file(&location.path).ok();
+            let fuzzy_hash = fuzzy_hash_file(&location.path).ok();
+
+            // A copy or restore keeps the contents but not the inode, so before tracking this
+            // as a new file, look for a record with the same hash whose inode has vanished
+            let orphan_id = match &sha1_hash {
+                Some(hash) => find_orphan(jf, hash, file_id, &location.path)?,
+                None => None,
+            };
+
+            if let Some(orphan_id) = orphan_id {
+                if let Some(position) = jf.file_position(orphan_id) {
+                    eprintln!("relinked file: \t{} -> {}", jf.files[position].last_known_name, last_known_name);
+                }
+                jf.rekey_file(orphan_id, file_id);
+                if let Some(position) = jf.file_position(file_id) {
+                    let file_data = &mut jf.files[position];
+                    file_data.last_known_name = last_known_name;
+                    file_data.parent_dir_inode = location.parent_dir_inode;
+                    file_data.fuzzy_hash = fuzzy_hash;
+                }
             } else {
                 let new_file = FileData {
-                    last_known_name: location.path.to_string_lossy().to_string(),
-                    file_inode: location.file_inode,
+                    last_known_name,
+                    file_inode: file_id.inode,
                     parent_dir_inode: location.parent_dir_inode,
+                    device_id: file_id.device_id,
+                    sha1_hash,
+                    fuzzy_hash,
                 };
                 jf.files.push(new_file);
+                jf.index.add_file(file_id, jf.files.len() - 1);
             }
-            Ok(file_inode) 
+            Ok(file_id)
         },
         None => {
             Err(format!("File '{}' not found in any directory", file_path).into())
@@ -42,139 +93,184 @@ pub fn handle_file(file_path: &str, jf: &mut TagsFile) -> Result<u64, Box<dyn Er
     }
 }
 
-fn find_file_with_inodes(file_path: &str) -> Result<Option<FileLocation>, Box<dyn Error>> {
-    let path = Path::new(file_path);
-    
-    if path.exists() {
-        let file_metadata = fs::metadata(path)?;
-        let file_inode = file_metadata.ino();
-        
-        // Fix for empty parent path - always use "." for current directory
-        let parent_path = if let Some(parent) = path.parent() {
-            if parent.as_os_str().is_empty() {
-                Path::new(".")
-            } else {
-                parent
-            }
-        } else {
-            Path::new(".")
-        };
-        
-        let parent_metadata = fs::metadata(parent_path)?;
-        let parent_dir_inode = parent_metadata.ino();
-        
-        let current_dir = std::env::current_dir()?;
-        let relative_path = if path.is_absolute() {
-            if let Ok(rel_path) = path.strip_prefix(&current_dir) {
-                rel_path.to_path_buf()
-            } else {
-                path.to_path_buf()
-            }
-        } else {
-            path.to_path_buf()
-        };
-        
-        return Ok(Some(FileLocation {
-            path: relative_path,
-            file_inode,
-            parent_dir_inode,
-        }));
+/// Follow the tracked file `file_id` at its last known `path` after an editor replaced it
+/// Editors that save by replacing the file leave the same path under a new inode, so the record
+/// is rekeyed to the current id; returns None when the path now holds a different tracked file
+pub fn follow_replaced_file(jf: &mut TagsFile, file_id: FileId, path: &str) -> Option<FileId> {
+    let current_id = FileId::from_metadata(&fs::metadata(path).ok()?);
+    if current_id == file_id {
+        return Some(file_id);
     }
-    
-    let file_name = match path.file_name() {
-        Some(name) => name,
+    if jf.file_position(current_id).is_some() {
+        return None;
+    }
+
+    jf.rekey_file(file_id, current_id);
+    if let Some(position) = jf.file_position(current_id) {
+        jf.files[position].sha1_hash = hash_file(Path::new(path)).ok();
+        jf.files[position].fuzzy_hash = fuzzy_hash_file(Path::new(path)).ok();
+    }
+    Some(current_id)
+}
+
+/// The vanished record that the untracked file `file_id` at `path` with contents `hash` replaces, if any
+/// Only a unique match on both sides counts: empty files, or contents shared with other
+/// vanished records or other untracked files, say nothing about which file this was
+fn find_orphan(jf: &mut TagsFile, hash: &str, file_id: FileId, path: &Path) -> Result<Option<FileId>, Box<dyn Error>> {
+    if fs::metadata(path)?.len() == 0 {
+        return Ok(None);
+    }
+
+    let vanished: Vec<FileId> = jf.files.iter()
+        .filter(|f| f.sha1_hash.as_deref() == Some(hash) && !is_record_present(f))
+        .map(|f| f.id())
+        .collect();
+    if vanished.len() != 1 {
+        return Ok(None);
+    }
+
+    let tracked_ids: HashSet<FileId> = jf.files.iter().map(|f| f.id()).collect();
+    let target_hashes = HashSet::from([hash.to_string()]);
+    let matches = find_files_by_hashes(&target_hashes, &tracked_ids, &jf.search_roots()?, &mut jf.paths)?;
+    match matches.get(hash).map(Vec::as_slice) {
+        Some([(_, only_id)]) if *only_id == file_id => Ok(Some(vanished[0])),
+        _ => Ok(None),
+    }
+}
+
+/// Check whether a tracked file is still at its last known path with its recorded inode
+fn is_record_present(file_data: &FileData) -> bool {
+    match fs::metadata(&file_data.last_known_name) {
+        Ok(metadata) => FileId::from_metadata(&metadata) == file_data.id(),
+        Err(_) => false,
+    }
+}
+
+/// Compute the similarity digest of a file's contents
+pub fn fuzzy_hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    Ok(fuzzy_hash(&mut file)?)
+}
+
+/// Compute the hex-encoded SHA-1 of a file's contents
+pub fn hash_file(path: &Path) -> Result<String, Box<dyn Error>> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha1::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Look for a file that is not at `file_path` by its name anywhere under `roots`
+fn find_file_by_name(file_path: &str, roots: &[String], cache: &mut PathCache) -> Result<Option<FileLocation>, Box<dyn Error>> {
+    let file_name = match Path::new(file_path).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
         None => return Ok(None),
     };
-    
-    for entry in WalkDir::new(".").parallelism(jwalk::Parallelism::RayonNewPool(4)) {
-        match entry {
-            Ok(entry) => {
-                if entry.file_name.eq_ignore_ascii_case(file_name) {
-                    let found_path = entry.path();
-                    let file_metadata = fs::metadata(&found_path)?;
-                    let file_inode = file_metadata.ino();
-                    
-                    // Same fix for parent path
-                    let parent_path = if let Some(parent) = found_path.parent() {
-                        if parent.as_os_str().is_empty() {
-                            Path::new(".")
-                        } else {
-                            parent
-                        }
-                    } else {
-                        Path::new(".")
-                    };
-                    
-                    let parent_metadata = fs::metadata(parent_path)?;
-                    let parent_dir_inode = parent_metadata.ino();
-                    
-                    let current_dir = std::env::current_dir()?;
-                    let relative_path = if found_path.is_absolute() {
-                        if let Ok(rel_path) = found_path.strip_prefix(&current_dir) {
-                            rel_path.to_path_buf()
-                        } else {
-                            found_path
-                        }
-                    } else {
-                        found_path
-                    };
-                    
-                    return Ok(Some(FileLocation {
-                        path: relative_path,
-                        file_inode,
-                        parent_dir_inode,
-                    }));
-                }
-            },
-            Err(e) => {
-                eprintln!("Error during directory traversal: {}", e);
+
+    // Refresh first so a match created since the last lookup is not missed
+    cache.refresh(roots)?;
+    let candidates = cache.paths_named(roots, &file_name);
+
+    // A bare name may match unrelated files in different folders; never guess between them
+    if candidates.len() > 1 {
+        return Err(format!("'{}' matches {} files ({}); give the path of the one you mean",
+            file_path, candidates.len(), candidates.join(", ")).into());
+    }
+
+    match candidates.first() {
+        Some(found_path) => Ok(Some(locate(Path::new(found_path))?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the file id and parent directory inode of an existing path
+fn locate(path: &Path) -> Result<FileLocation, Box<dyn Error>> {
+    let file_metadata = fs::metadata(path)?;
+    let file_id = FileId::from_metadata(&file_metadata);
+
+    // Fix for empty parent path - always use "." for current directory
+    let parent_path = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let parent_dir_inode = fs::metadata(parent_path)?.ino();
+
+    Ok(FileLocation {
+        path: path.to_path_buf(),
+        file_id,
+        parent_dir_inode,
+    })
+}
+
+/// Look up every one of `target_ids` under `roots`, returning the path found for each
+/// The cached listing is tried first; the tree is only re-read when some id is not found there
+pub fn find_filenames_by_inodes(target_ids: &HashSet<FileId>, roots: &[String], cache: &mut PathCache) -> Result<HashMap<FileId, String>, Box<dyn Error>> {
+    if target_ids.is_empty() {
+        return Ok(HashMap::new());
+    }
+
+    let mut found = cached_paths_of(target_ids, cache, roots);
+    if found.len() < target_ids.len() {
+        cache.refresh(roots)?;
+        found = cached_paths_of(target_ids, cache, roots);
+    }
+
+    Ok(found)
+}
+
+/// The first listed path of each of `target_ids` whose file still has that id on disk
+/// The database's own files are skipped, since they often reuse the inode of a deleted file
+fn cached_paths_of(target_ids: &HashSet<FileId>, cache: &PathCache, roots: &[String]) -> HashMap<FileId, String> {
+    let mut found = HashMap::new();
+
+    for (path, file_id) in &cache.files(roots) {
+        if target_ids.contains(file_id) && !found.contains_key(file_id) && !cache.is_database_file(path) {
+            let still_there = fs::metadata(path).is_ok_and(|metadata| FileId::from_metadata(&metadata) == *file_id);
+            if still_there {
+                found.insert(*file_id, path.clone());
             }
         }
     }
-    
-    Ok(None)
+
+    found
 }
 
+/// The files found with each content hash, as path and file id
+pub type HashMatches = HashMap<String, Vec<(String, FileId)>>;
 
-pub fn find_filename_by_inode(target_inode: u64) -> Result<Option<String>, Box<dyn Error>> {
-    //println!("Searching for file with inode: {}", target_inode);
-    
-    // Start recursive search from current directory
-    for entry in WalkDir::new(".").parallelism(jwalk::Parallelism::RayonNewPool(4)) {
-        match entry {
-            Ok(entry) => {
-                // Skip directories to speed up the search (optional)
-                if entry.file_type.is_dir() {
-                    continue;
-                }
-                
-                // Get the full path
-                let path = entry.path();
-                
-                // Get metadata to check inode
-                match std::fs::metadata(&path) {
-                    Ok(metadata) => {
-                        let file_inode = metadata.ino();
-                        
-                        // Check if this is the file we're looking for
-                        if file_inode == target_inode {
-                            //println!("Found matching file: {:?}", path);
-                            return Ok(Some(path.to_string_lossy().to_string()));
-                        }
-                    },
-                    Err(e) => {
-                        // Log the error but continue searching
-                        eprintln!("Error reading metadata for {:?}: {}", path, e);
-                    }
-                }
-            },
-            Err(e) => {
-                eprintln!("Error during directory traversal: {}", e);
+/// Search every file under `roots` for contents that hash to any of `target_hashes`
+/// Files whose id is in `skip_ids` are already tracked and are not hashed; the database's own
+/// files, and empty files whose contents say nothing about which file they were, are skipped
+/// Returns every match for each hash so callers can refuse to pick between several
+pub fn find_files_by_hashes(
+    target_hashes: &HashSet<String>,
+    skip_ids: &HashSet<FileId>,
+    roots: &[String],
+    cache: &mut PathCache
+) -> Result<HashMatches, Box<dyn Error>> {
+    let mut found = HashMatches::new();
+    if target_hashes.is_empty() {
+        return Ok(found);
+    }
+
+    for (path, file_id) in walk_files_with_inodes(roots, cache)? {
+        if skip_ids.contains(&file_id) || cache.is_database_file(&path) || fs::metadata(&path).map_or(true, |metadata| metadata.len() == 0) {
+            continue;
+        }
+
+        if let Ok(hash) = hash_file(Path::new(&path)) {
+            if target_hashes.contains(&hash) {
+                found.entry(hash).or_default().push((path, file_id));
             }
         }
     }
-    
-    // If we get here, no matching file was found
-    //println!("No file with inode {} found", target_inode);
-    Ok(None)
-}
\ No newline at end of file
+
+    Ok(found)
+}
+
+/// Every regular file under `roots`, each path with its file id
+/// Used to scan whole trees, e.g. when listing files that have not been tagged
+pub fn walk_files_with_inodes(roots: &[String], cache: &mut PathCache) -> Result<Vec<(String, FileId)>, Box<dyn Error>> {
+    cache.refresh(roots)?;
+    Ok(cache.files(roots))
+}
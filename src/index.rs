@@ -0,0 +1,243 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::common::{EntsTag, FileId, TagsFile};
+use crate::relationship::is_visible_tag;
+
+/// Lookup tables over a loaded `TagsFile`, so finding a tag by name or a file by id does not scan every record
+/// Built by `TagsFile::build_index` after loading and kept current wherever assignments or file records change
+#[derive(Debug, Clone, Default)]
+pub struct TagIndex {
+    // Position in `tags` of each tag by its path, preferring the visible one if a path repeats
+    tags_by_path: HashMap<String, usize>,
+    // Positions in `tags` of every tag with each leaf name, which may repeat across branches
+    tags_by_name: HashMap<String, Vec<usize>>,
+    // Position in `files` of the record for each file id
+    files_by_id: HashMap<FileId, usize>,
+    // Positions in `tags` of the visible tags assigned to each `device:inode` file id
+    tags_by_file: HashMap<String, HashSet<usize>>,
+}
+
+impl TagIndex {
+    /// Note that the tag at `tag_position` was assigned to, or removed from, `file_id`
+    pub fn set_assigned(&mut self, tag_position: usize, file_id: &str, assigned: bool) {
+        if assigned {
+            self.tags_by_file.entry(file_id.to_string()).or_default().insert(tag_position);
+        } else if let Some(positions) = self.tags_by_file.get_mut(file_id) {
+            positions.remove(&tag_position);
+        }
+    }
+
+    /// Note that a record for `file_id` was added to `files` at `position`
+    pub fn add_file(&mut self, file_id: FileId, position: usize) {
+        self.files_by_id.entry(file_id).or_insert(position);
+    }
+
+    /// Note that the record at `position` was removed from `files`, moving the ones after it up
+    pub fn remove_file(&mut self, position: usize) {
+        self.files_by_id.retain(|_, p| *p != position);
+        for p in self.files_by_id.values_mut() {
+            if *p > position {
+                *p -= 1;
+            }
+        }
+    }
+
+    /// Note that the record and assignments of `old_id` now belong to `new_id`
+    pub fn rekey_file(&mut self, old_id: FileId, new_id: FileId) {
+        if let Some(position) = self.files_by_id.remove(&old_id) {
+            self.files_by_id.insert(new_id, position);
+        }
+        if let Some(positions) = self.tags_by_file.remove(&old_id.to_string()) {
+            self.tags_by_file.entry(new_id.to_string()).or_default().extend(positions);
+        }
+    }
+}
+
+impl TagsFile {
+    /// Rebuild every lookup table from scratch
+    /// Needed after loading; later changes keep the tables current in place
+    pub fn build_index(&mut self) {
+        let mut index = TagIndex::default();
+
+        for (position, tag) in self.tags.iter().enumerate() {
+            let visible = is_visible_tag(tag);
+            let path = tag.path();
+            if visible || !index.tags_by_path.contains_key(&path) {
+                index.tags_by_path.insert(path, position);
+            }
+            index.tags_by_name.entry(tag.name.clone()).or_default().push(position);
+
+            if visible {
+                for file_id in tag.files.iter().flatten() {
+                    index.set_assigned(position, file_id, true);
+                }
+            }
+        }
+
+        for (position, file_data) in self.files.iter().enumerate() {
+            index.add_file(file_data.id(), position);
+        }
+
+        self.index = index;
+    }
+
+    /// Position in `tags` of the tag at `path`, visible or not
+    /// A bare leaf name is also accepted when only one tag has it, preferring a visible one,
+    /// so records written before tags were addressed by path still resolve
+    pub fn tag_position(&self, path: &str) -> Option<usize> {
+        if let Some(position) = self.index.tags_by_path.get(path) {
+            return Some(*position);
+        }
+
+        let positions = self.index.tags_by_name.get(path)?;
+        let visible: Vec<usize> = positions.iter().copied().filter(|p| is_visible_tag(&self.tags[*p])).collect();
+        match (visible.as_slice(), positions.as_slice()) {
+            ([position], _) | ([], [position]) => Some(*position),
+            _ => None,
+        }
+    }
+
+    /// The visible tag at `path`
+    pub fn find_tag(&self, path: &str) -> Option<&EntsTag> {
+        self.tag_position(path)
+            .map(|position| &self.tags[position])
+            .filter(|tag| is_visible_tag(tag))
+    }
+
+    /// Position in `tags` of the visible tag a user means by `name`
+    /// `name` may be an alias, a full path, the trailing part of a path such as `invoices/2023`,
+    /// or a bare leaf name; the error explains a name that matches no tag or several
+    pub fn resolve_tag(&self, name: &str) -> Result<usize, String> {
+        let name = self.aliases.get(name).map_or(name, String::as_str);
+
+        if let Some(position) = self.index.tags_by_path.get(name).filter(|p| is_visible_tag(&self.tags[**p])) {
+            return Ok(*position);
+        }
+
+        let leaf = name.rsplit('/').next().unwrap_or(name);
+        let suffix = format!("/{}", name);
+        let matches: Vec<usize> = self.index.tags_by_name.get(leaf)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|p| is_visible_tag(&self.tags[*p]))
+            .filter(|p| {
+                let path = self.tags[*p].path();
+                path == name || path.ends_with(&suffix)
+            })
+            .collect();
+
+        match matches.as_slice() {
+            [] => Err(format!("tag or alias does not exist: {}", name)),
+            [position] => Ok(*position),
+            _ => {
+                let mut paths: Vec<String> = matches.iter().map(|p| self.tags[*p].path()).collect();
+                paths.sort();
+                Err(format!("tag '{}' is ambiguous; use one of: {}", name, paths.join(", ")))
+            }
+        }
+    }
+
+    /// Position in `files` of the record for `file_id`
+    pub fn file_position(&self, file_id: FileId) -> Option<usize> {
+        self.index.files_by_id.get(&file_id).copied()
+    }
+
+    /// Every visible tag assigned to the file with id `file_id`
+    pub fn tags_of_file<'a>(&'a self, file_id: &str) -> impl Iterator<Item = &'a EntsTag> + 'a {
+        self.index.tags_by_file.get(file_id)
+            .into_iter()
+            .flatten()
+            .map(|position| &self.tags[*position])
+    }
+
+    /// The ids of every file assigned to at least one visible tag
+    pub fn tagged_files(&self) -> impl Iterator<Item = &String> {
+        self.index.tags_by_file.iter()
+            .filter(|(_, positions)| !positions.is_empty())
+            .map(|(file_id, _)| file_id)
+    }
+
+    /// Whether the tag at `tag_position` is assigned to the file with id `file_id`
+    pub fn has_assignment(&self, tag_position: usize, file_id: &str) -> bool {
+        self.index.tags_by_file.get(file_id).is_some_and(|positions| positions.contains(&tag_position))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common::{FileData, TagType};
+
+    fn tag(name: &str, files: &[&str], show: bool) -> EntsTag {
+        let mut tag = EntsTag::new(name.to_string(), TagType::Normal, Vec::new());
+        tag.files = Some(files.iter().map(|f| f.to_string()).collect());
+        tag.show = Some(show);
+        tag
+    }
+
+    #[test]
+    fn test_index_follows_assignments_and_rekeys() {
+        let mut tags_file = TagsFile {
+            files: vec![FileData {
+                last_known_name: "a.txt".to_string(),
+                file_inode: 2,
+                parent_dir_inode: 1,
+                device_id: 1,
+                sha1_hash: None,
+                fuzzy_hash: None,
+            }],
+            tags: vec![tag("old", &["1:2"], false), tag("a", &["1:2"], true), tag("b", &[], true)],
+            ..TagsFile::default()
+        };
+        tags_file.build_index();
+
+        // Hidden tags are found by position but never reported as assigned
+        assert_eq!(tags_file.tag_position("old"), Some(0));
+        assert!(tags_file.find_tag("old").is_none());
+        let names: Vec<&str> = tags_file.tags_of_file("1:2").map(|t| t.name.as_str()).collect();
+        assert_eq!(names, vec!["a"]);
+
+        tags_file.index.set_assigned(2, "1:2", true);
+        assert!(tags_file.has_assignment(2, "1:2"));
+
+        let new_id = FileId { device_id: 1, inode: 3 };
+        tags_file.rekey_file(FileId { device_id: 1, inode: 2 }, new_id);
+        assert_eq!(tags_file.file_position(new_id), Some(0));
+        assert!(tags_file.has_assignment(1, "1:3"));
+        assert_eq!(tags_file.tagged_files().count(), 1);
+
+        // A stale record already holding the new id is dropped and the records after it move up
+        let record = |inode: u64| FileData { file_inode: inode, ..tags_file.files[0].clone() };
+        tags_file.files.extend([record(4), record(5)]);
+        tags_file.build_index();
+        tags_file.rekey_file(new_id, FileId { device_id: 1, inode: 4 });
+        let positions: Vec<Option<usize>> = (3..=5).map(|inode| tags_file.file_position(FileId { device_id: 1, inode })).collect();
+        assert_eq!(positions, vec![None, Some(0), Some(1)]);
+        assert!(tags_file.has_assignment(1, "1:4") && !tags_file.has_assignment(1, "1:3"));
+    }
+
+    #[test]
+    fn test_resolve_tag_by_path() {
+        let mut tags_file = TagsFile {
+            tags: vec![
+                EntsTag::new("invoices".to_string(), TagType::Normal, Vec::new()),
+                EntsTag::new("2023".to_string(), TagType::Normal, vec!["invoices".to_string()]),
+                EntsTag::new("photos".to_string(), TagType::Normal, Vec::new()),
+                EntsTag::new("2023".to_string(), TagType::Normal, vec!["photos".to_string()]),
+                EntsTag::new("raw".to_string(), TagType::Normal, vec!["photos".to_string(), "2023".to_string()]),
+            ],
+            ..TagsFile::default()
+        };
+        tags_file.aliases.insert("shots".to_string(), "photos/2023".to_string());
+        tags_file.build_index();
+
+        assert_eq!(tags_file.resolve_tag("invoices/2023"), Ok(1));
+        assert_eq!(tags_file.resolve_tag("shots"), Ok(3));
+        assert_eq!(tags_file.resolve_tag("2023/raw"), Ok(4));
+        assert_eq!(tags_file.resolve_tag("raw"), Ok(4));
+        assert_eq!(tags_file.resolve_tag("2023"), Err("tag '2023' is ambiguous; use one of: invoices/2023, photos/2023".to_string()));
+        assert!(tags_file.resolve_tag("voices/2023").is_err());
+        assert_eq!(tags_file.tag_position("2023"), None);
+    }
+}
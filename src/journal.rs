@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+
+use crate::common::{Assignment, TagsFile, unix_timestamp};
+use crate::relationship::is_visible_tag;
+
+/// One tag assigned to or removed from one file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TagChange {
+    pub tag: String,
+    // `device:inode` id the change applies to, and the path it was made through
+    pub file: String,
+    pub path: String,
+    // Set on assignments made with --force, and on the removals they forced
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub forced: bool,
+    // For a forced removal, the tag whose assignment caused it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cause: Option<String>,
+}
+
+/// Something a command did to the database, as written to the journal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum JournalRecord {
+    Add(TagChange),
+    Remove(TagChange),
+    // Transaction `target` was reverted, or re-applied after being reverted
+    Undo { target: u64 },
+    Redo { target: u64 },
+    // A tracked file was found again under a new `device:inode` id; never undone itself,
+    // but older changes to `from` are applied to `to` when they are undone or redone
+    Rekey { from: String, to: String },
+}
+
+/// A journal line; every record written by one command shares a transaction number
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub txn: u64,
+    // Seconds since the Unix epoch
+    pub timestamp: u64,
+    #[serde(flatten)]
+    pub record: JournalRecord,
+}
+
+/// Append-only log of every change made to a database, kept as JSON lines in `<database>.journal`
+pub struct Journal {
+    pub path: PathBuf,
+}
+
+impl Journal {
+    /// The journal that belongs to the database at `database`
+    pub fn beside(database: &Path) -> Journal {
+        let mut path = database.as_os_str().to_owned();
+        path.push(".journal");
+        Journal { path: PathBuf::from(path) }
+    }
+
+    /// Every entry written so far, oldest first
+    pub fn read(&self) -> Result<Vec<JournalEntry>, Box<dyn Error>> {
+        let file = match fs::File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let entry = serde_json::from_str(&line)
+                .map_err(|e| format!("{} line {}: {}", self.path.display(), number + 1, e))?;
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    /// Write `records` as one new transaction
+    pub fn append(&self, records: &[JournalRecord]) -> Result<(), Box<dyn Error>> {
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let txn = self.read()?.last().map_or(1, |entry| entry.txn + 1);
+        let timestamp = unix_timestamp();
+
+        let mut lines = String::new();
+        for record in records {
+            let entry = JournalEntry { txn, timestamp, record: record.clone() };
+            lines.push_str(&serde_json::to_string(&entry)?);
+            lines.push('\n');
+        }
+
+        let mut file = fs::OpenOptions::new().create(true).append(true).open(&self.path)?;
+        file.write_all(lines.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Work out which transactions can currently be undone and redone, most recent last
+/// Undoing moves a transaction to the redo stack and redoing moves it back;
+/// any new change empties the redo stack
+fn undo_redo_stacks(entries: &[JournalEntry]) -> (Vec<u64>, Vec<u64>) {
+    let mut done = Vec::new();
+    let mut undone = Vec::new();
+    let mut last_opened = None;
+
+    for entry in entries {
+        match entry.record {
+            JournalRecord::Undo { target } => {
+                done.retain(|t| *t != target);
+                undone.push(target);
+            },
+            JournalRecord::Redo { target } => {
+                undone.retain(|t| *t != target);
+                done.push(target);
+            },
+            // Rekeys follow the file system and are not something to undo
+            JournalRecord::Rekey { .. } => {},
+            // Only the first change of a transaction opens it
+            _ if last_opened == Some(entry.txn) => {},
+            _ => {
+                done.push(entry.txn);
+                undone.clear();
+                last_opened = Some(entry.txn);
+            },
+        }
+    }
+
+    (done, undone)
+}
+
+/// The id the file changed through `entries[index]` has now, following every later rekey in order
+fn current_file_id(entries: &[JournalEntry], index: usize, file_id: &str) -> String {
+    let mut file_id = file_id.to_string();
+    for entry in &entries[index + 1..] {
+        if let JournalRecord::Rekey { from, to } = &entry.record {
+            if *from == file_id {
+                file_id = to.clone();
+            }
+        }
+    }
+    file_id
+}
+
+/// Assign or unassign `change.tag` on `change.file`; returns whether anything changed
+/// A restored assignment is stamped with the current time, and is only marked forced when redoing a forced one
+fn apply_change(tags_file: &mut TagsFile, change: &TagChange, assign: bool, forced: bool) -> bool {
+    let tag_position = match tags_file.tag_position(&change.tag) {
+        Some(position) => position,
+        None => return false,
+    };
+    let tag = &mut tags_file.tags[tag_position];
+    let visible = is_visible_tag(tag);
+    let files = tag.files.get_or_insert_with(Vec::new);
+
+    let changed = match files.iter().position(|f| *f == change.file) {
+        Some(_) if assign => false,
+        None if assign => {
+            files.push(change.file.clone());
+            tag.assigned.insert(change.file.clone(), Assignment::now(forced));
+            true
+        },
+        Some(position) => {
+            files.remove(position);
+            tag.assigned.remove(&change.file);
+            true
+        },
+        None => false,
+    };
+
+    if changed && visible {
+        tags_file.index.set_assigned(tag_position, &change.file, assign);
+    }
+    changed
+}
+
+/// Revert the most recent transaction, or with `redo` re-apply the most recently reverted one
+/// The reversal is itself journaled, so the journal stays append-only
+pub fn undo_command(tags_file: &mut TagsFile, journal: &Journal, redo: bool) -> Result<(), Box<dyn Error>> {
+    let entries = journal.read()?;
+    let (done, undone) = undo_redo_stacks(&entries);
+
+    let target = match if redo { undone.last() } else { done.last() } {
+        Some(txn) => *txn,
+        None => {
+            println!("nothing to {}", if redo { "redo" } else { "undo" });
+            return Ok(());
+        }
+    };
+
+    // Files rekeyed since the transaction are changed under the id they have now
+    let mut changes: Vec<(bool, TagChange)> = entries.iter()
+        .enumerate()
+        .filter(|(_, entry)| entry.txn == target)
+        .filter_map(|(index, entry)| match &entry.record {
+            JournalRecord::Add(change) => Some((true, index, change)),
+            JournalRecord::Remove(change) => Some((false, index, change)),
+            _ => None,
+        })
+        .map(|(assign, index, change)| (assign, TagChange { file: current_file_id(&entries, index, &change.file), ..change.clone() }))
+        .collect();
+
+    // Undo walks the transaction backwards, flipping each change
+    if !redo {
+        changes.reverse();
+        for change in &mut changes {
+            change.0 = !change.0;
+        }
+    }
+
+    for (assign, change) in changes {
+        let verb = if assign { "assigned file, tag:" } else { "removed  file, tag:" };
+        if apply_change(tags_file, &change, assign, redo && change.forced) {
+            println!("{} \t{} \t{}", verb, change.path, change.tag);
+        } else {
+            println!("already {} \t{} \t{}", verb, change.path, change.tag);
+        }
+    }
+
+    tags_file.journal.push(if redo { JournalRecord::Redo { target } } else { JournalRecord::Undo { target } });
+    tags_file.dirty = true;
+    println!("{} transaction {}", if redo { "redid" } else { "undid" }, target);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(txn: u64, record: JournalRecord) -> JournalEntry {
+        JournalEntry { txn, timestamp: 0, record }
+    }
+
+    fn add(tag: &str) -> JournalRecord {
+        JournalRecord::Add(TagChange {
+            tag: tag.to_string(),
+            file: "1:1".to_string(),
+            path: "a.txt".to_string(),
+            forced: false,
+            cause: None,
+        })
+    }
+
+    #[test]
+    fn test_undo_redo_stacks() {
+        let mut entries = vec![
+            entry(1, add("a")),
+            entry(1, add("b")),
+            entry(2, add("c")),
+            entry(3, JournalRecord::Undo { target: 2 }),
+        ];
+        assert_eq!(undo_redo_stacks(&entries), (vec![1], vec![2]));
+
+        entries.push(entry(4, JournalRecord::Redo { target: 2 }));
+        assert_eq!(undo_redo_stacks(&entries), (vec![1, 2], vec![]));
+
+        // A new change after an undo discards what could have been redone
+        entries.push(entry(5, JournalRecord::Undo { target: 2 }));
+        entries.push(entry(6, add("d")));
+        assert_eq!(undo_redo_stacks(&entries), (vec![1, 6], vec![]));
+
+        // A rekey is not a transaction of its own and does not hide the change after it
+        entries.push(entry(7, JournalRecord::Rekey { from: "1:1".to_string(), to: "1:2".to_string() }));
+        entries.push(entry(7, add("e")));
+        assert_eq!(undo_redo_stacks(&entries), (vec![1, 6, 7], vec![]));
+    }
+
+    #[test]
+    fn test_changes_follow_later_rekeys() {
+        let rekey = |from: &str, to: &str| JournalRecord::Rekey { from: from.to_string(), to: to.to_string() };
+        let entries = vec![
+            entry(1, add("a")),
+            entry(2, rekey("1:1", "1:5")),
+            entry(3, rekey("1:9", "1:1")),
+            entry(4, rekey("1:5", "1:7")),
+        ];
+        assert_eq!(current_file_id(&entries, 0, "1:1"), "1:7");
+        assert_eq!(current_file_id(&entries, 1, "1:9"), "1:1");
+    }
+}
@@ -3,6 +3,7 @@ use std::error::Error;
 use std::collections::HashSet;
 use std::env;
 use std::fs;
+use std::time::Duration;
 
 use std::os::raw::c_char;
 
@@ -13,17 +14,32 @@ mod handle_file;
 mod merge_tags;
 mod options;
 mod eval_shell;
+mod expression;
+mod fuzzy;
+mod storage;
+mod workspace;
+mod migrate;
+mod journal;
+mod index;
+mod diagnostic;
+mod path_cache;
 
 use parser::parse_ents;
 use options::Args;
 
-use crate::common::{TagType, EntsTag, TagsFile, read_tags_from_json, save_tags_to_json};
+use crate::common::{TagType, TagsFile};
+use crate::storage::{open_store, convert_store, database_path, default_database_path, global_database_path};
+use crate::workspace::Workspace;
 
 use relationship::{
-    Operation, is_visible_tag, assign_bidir_file_tag_rel, filter_command, represent_inspect
+    Operation, assign_bidir_file_tag_rel, filter_command, represent_inspect,
+    difference_inodes, resolve_inodes, untagged_command, relink_command, root_command
 };
 use eval_shell::print_shell_functions;
 use merge_tags::merge_tags;
+use expression::query_command;
+use migrate::migrate_command;
+use journal::undo_command;
 
 // // External C functions
 // extern "C" {
@@ -42,7 +58,7 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     if raw_args.len() < 2 {
-        println!("Usage: prlents <ttf|ftt|fil|int|insp|process>");
+        println!("Usage: prlents <ttf|ftt|fil|int|diff|query|untagged|relink|insp|root|process|undo|redo|convert|migrate>");
         return Ok(());
     }
     
@@ -55,47 +71,97 @@ fn main() -> Result<(), Box<dyn Error>> {
     }
 
     let command = &args.command;
+    let lock_timeout = Duration::from_secs(args.lock_timeout);
 
-    if command == "process" || command == "parse" {
-        let current_dir = std::env::current_dir()?;
+    if command == "convert" {
+        // convert <from> <to>, or convert <to> to re-encode the current database
+        let (from, to) = match args.args.len() {
+            1 => (args.db.clone().unwrap_or_else(|| default_database_path().to_string_lossy().to_string()), &args.args[0]),
+            2 => (args.args[0].clone(), &args.args[1]),
+            _ => {
+                println!("usage: prlents convert [from] <to>");
+                return Ok(());
+            }
+        };
+        // Both ends would take the same lock and wait on each other until the timeout
+        let same_file = match (fs::canonicalize(&from), fs::canonicalize(to)) {
+            (Ok(from_path), Ok(to_path)) => from_path == to_path,
+            _ => false,
+        };
+        if same_file {
+            return Err(format!("cannot convert {} into itself; give a different destination", from).into());
+        }
+
+        let (from_store, to_store) = (open_store(Some(&from)), open_store(Some(to)));
+        let _from_lock = from_store.lock(lock_timeout)?;
+        let _to_lock = to_store.lock(lock_timeout)?;
+        convert_store(from_store.as_ref(), to_store.as_ref())?;
+        return Ok(());
+    }
 
+    // Work from the directory holding the database so stored paths resolve from any subdirectory
+    // The global database stores absolute paths, so it works from wherever the user is
+    let cwd = env::current_dir()?;
+    let workspace = match &args.db {
+        _ if args.global => Workspace::global(&global_database_path()?, &cwd),
+        Some(database) => Workspace::from_database(database, &cwd)?,
+        None => Workspace::discover(&cwd),
+    };
+    workspace.enter()?;
+
+    if command == "process" || command == "parse" {
         let file_path = if !args.args.is_empty() {
-            &args.args[0]
+            workspace.to_root_relative(&args.args[0])
         } else {
-            "tags.ents" // Default if no file specified
+            "tags.ents".to_string() // Default if no file specified
         };
         
-        match parse_ents(file_path) {
+        match parse_ents(&file_path, args.indent_width) {
             Ok(parsed_tags_file) => {
-                let parsed_obj = &parsed_tags_file;
-                let json_content = serde_json::to_string_pretty(parsed_obj)?; // it needs to be mutable
+                let store = open_store(workspace.database.as_deref());
+                let _lock = store.lock(lock_timeout)?;
+                merge_tags(parsed_tags_file, store.as_ref())?;
 
-                if !Path::new("tags.json").exists() {
-                    fs::write("tags.json", json_content + "\n")?;
-                } else {
-                    merge_tags(json_content, "tags.json");
-                }
-
-                println!("Successfully parsed {} and saved to tags.json", file_path);
+                println!("Successfully parsed {} and saved to {}", file_path, store.describe());
             },
             Err(e) => {
-                println!("Error: {}", e);
-                return Err(e);
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
         }
         return Ok(());
     }    
-    
-    let mut tags_file = match read_tags_from_json() {
-        Ok(tf) => tf,
-        Err(e) => {
-            return Err(e);
-        }
-    };
-    
+
+    if command == "migrate" {
+        let store = open_store(workspace.database.as_deref());
+        let _lock = store.lock(lock_timeout)?;
+        return migrate_command(store.as_ref(), args.dry_run);
+    }
+
+    if command == "undo" || command == "redo" {
+        let store = open_store(workspace.database.as_deref());
+        let _lock = store.lock(lock_timeout)?;
+        let journal = store.journal().ok_or("this database keeps no journal")?;
+        return store.update(&mut |tags_file| undo_command(tags_file, &journal, command == "redo"));
+    }
+
+    // Hold the lock across the whole read-modify-write so parallel commands cannot lose updates
+    let store = open_store(workspace.database.as_deref());
+    let _lock = store.lock(lock_timeout)?;
+    store.update(&mut |tags_file| run_command(&args, &workspace, tags_file))
+}
+
+/// Run a command that reads or modifies the tag database
+/// File arguments are taken relative to where the user is and translated to the workspace root,
+/// and listed files are translated back; commands mark `tags_file` dirty when it needs to be saved
+fn run_command(args: &Args, workspace: &Workspace, tags_file: &mut TagsFile) -> Result<(), Box<dyn Error>> {
+    let command = &args.command;
+    tags_file.paths.set_gitignore(args.gitignore);
+    tags_file.global = workspace.global;
+
     if command == "filter" || command == "fil" || command == "union" || command == "un" {
-        for file in filter_command(&mut tags_file, &args.args, args.explicit)? {
-            println!("{}", file.trim());
+        for file in filter_command(tags_file, &args.args, args.explicit)? {
+            println!("{}", workspace.to_display(file.trim()));
         }
     
     } else if command == "intersection" || command  == "intersect" || command == "int" {
@@ -104,26 +170,79 @@ fn main() -> Result<(), Box<dyn Error>> {
             eprintln!("need at least one tag for intersection");
             std::process::exit(1);
         } else if args.args.len() == 1 {
-            for file in filter_command(&mut tags_file, &args.args, args.explicit)? {
-                println!("{}", file.trim());
+            for file in filter_command(tags_file, &args.args, args.explicit)? {
+                println!("{}", workspace.to_display(file.trim()));
             }
             return Ok(())
         } else {
 
-            let mut result: HashSet<_> = filter_command(&mut tags_file, &[args.args[0].clone()], args.explicit)?.iter().cloned().collect();
+            let mut result: HashSet<_> = filter_command(tags_file, &[args.args[0].clone()], args.explicit)?.iter().cloned().collect();
             for tag in &args.args[1..] {
-                let other_result: HashSet<_> = filter_command(&mut tags_file, &[tag.clone()], args.explicit)?.iter().cloned().collect();
+                let other_result: HashSet<_> = filter_command(tags_file, &[tag.clone()], args.explicit)?.iter().cloned().collect();
                 result = result.intersection(&other_result).cloned().collect();
             }
             
             let vec: Vec<_> = result.into_iter().collect(); 
             for file in vec {
-                println!("{}", file.trim());
+                println!("{}", workspace.to_display(file.trim()));
             }
         }
 
+    } else if command == "difference" || command == "diff" || command == "except" {
+
+        if args.args.len() < 2 {
+            eprintln!("need a tag to include and at least one tag to exclude");
+            std::process::exit(1);
+        }
+
+        let inodes = difference_inodes(tags_file, &args.args[..1], &args.args[1..], args.explicit)?;
+        for file in resolve_inodes(tags_file, &inodes)? {
+            println!("{}", workspace.to_display(file.trim()));
+        }
+
+    } else if command == "query" || command == "q" {
+
+        if args.args.is_empty() {
+            eprintln!("need a query, e.g. '(photos or scans) and 2023 and not private'");
+            std::process::exit(1);
+        }
+
+        for file in query_command(tags_file, &args.args.join(" "), args.explicit)? {
+            println!("{}", workspace.to_display(file.trim()));
+        }
+
+    } else if command == "untagged" || command == "inbox" {
+        // With no paths given, scan from where the user is rather than the whole tree
+        let roots: Vec<String> = if args.args.is_empty() {
+            vec![workspace.to_root_relative(".")]
+        } else {
+            args.args.iter().map(|path| workspace.to_root_relative(path)).collect()
+        };
+
+        for file in untagged_command(tags_file, &roots, &args.ext, &database_path(workspace.database.as_deref()))? {
+            println!("{}", workspace.to_display(&file));
+        }
+
+    } else if command == "relink" {
+        relink_command(tags_file, args.apply, args.min_score, &database_path(workspace.database.as_deref()))?;
+
+    } else if command == "root" {
+        if !workspace.global {
+            println!("roots belong to the global database; use prlents -g root");
+            return Ok(());
+        }
+
+        let action = args.args.first().map(String::as_str).unwrap_or("ls");
+        let paths: Vec<String> = args.args.iter().skip(1).map(|path| workspace.to_root_relative(path)).collect();
+        root_command(tags_file, action, &paths)?;
+
     } else if command == "inspect" || command == "insp" {
-        represent_inspect(&mut tags_file, &args.args, args.quiet)?;
+        let files: Vec<String> = args.args.iter().map(|file| workspace.to_root_relative(file)).collect();
+        if let Some(missing) = files.iter().find(|file| args.strict && !Path::new(file).exists()) {
+            println!("file does not exist: {}", missing);
+            return Ok(());
+        }
+        represent_inspect(tags_file, &files, args.quiet)?;
         
     } else {
         if command != "tagtofiles" && command != "ttf" && command != "filetotags" && command != "ftt" {
@@ -146,31 +265,35 @@ fn main() -> Result<(), Box<dyn Error>> {
             let arguments = &args.args[2..];
             
             if command == "tagtofiles" || command == "ttf" {
-                // Resolve the actual tag name from aliases
-                let display_tag_name = match tags_file.aliases.get(monad) {
-                    Some(actual_name) => actual_name,
-                    None => monad,
+                // Resolve aliases and bare or path-qualified names to the tag
+                let tag_position = match tags_file.resolve_tag(monad) {
+                    Ok(position) => position,
+                    Err(message) => {
+                        println!("{}", message);
+                        return Ok(());
+                    }
                 };
+
+                if tags_file.tags[tag_position].tag_type == TagType::Dud {
+                    println!("cannot assign dud tag to files: \t{}", monad);
+                    return Ok(());
+                }
                 
-                let foo = tags_file.tags.iter()
-                    .find(|t| t.name == *display_tag_name && is_visible_tag(t));
-                
-                if let Some(foo) = foo {
-                    if foo.tag_type == TagType::Dud {
-                        println!("cannot assign dud tag to files: \t{}", monad);
-                        return Ok(());
+                for file in arguments {
+                    let file = workspace.to_root_relative(file);
+                    if args.strict && !Path::new(&file).exists() {
+                        println!("file does not exist: {}", file);
+                        continue;
                     }
-                    
-                    for file in arguments {
-                        assign_bidir_file_tag_rel(file, monad, operation, &mut tags_file, args.force)?;
+                    // One file that cannot be found, e.g. an ambiguous name, should not undo the others
+                    match assign_bidir_file_tag_rel(&file, monad, operation, tags_file, args.force) {
+                        Ok(()) => tags_file.dirty = true,
+                        Err(e) => println!("{}", e),
                     }
-                    
-                    save_tags_to_json(&tags_file)?;
-                } else {
-                    println!("tag does not exist: {}", monad);
                 }
 
             } else if command == "filetotags" || command == "ftt" {
+                let monad = &workspace.to_root_relative(monad);
                 if !Path::new(monad).exists() {
                     println!("file does not exist: {}", monad);
                     return Ok(());
@@ -183,10 +306,11 @@ fn main() -> Result<(), Box<dyn Error>> {
 
                 //if there isn't a tag for this, the "tag does not exist" error message is handled in bidirrel
                 for tag in arguments {
-                    assign_bidir_file_tag_rel(monad, tag, operation, &mut tags_file, args.force)?;
+                    match assign_bidir_file_tag_rel(monad, tag, operation, tags_file, args.force) {
+                        Ok(()) => tags_file.dirty = true,
+                        Err(e) => println!("{}", e),
+                    }
                 }
-                
-                save_tags_to_json(&tags_file)?;
             } else {
                 println!("error: invalid command '{}'", command);
             }
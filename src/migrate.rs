@@ -0,0 +1,162 @@
+use std::error::Error;
+use serde_json::Value;
+
+use crate::common::TagsFile;
+use crate::storage::TagStore;
+
+/// The layout version written by this build
+pub const CURRENT_VERSION: u32 = 1;
+
+/// One step of the upgrade pipeline, from version `from` to `from + 1`
+struct Migration {
+    from: u32,
+    description: &'static str,
+    apply: fn(&mut TagsFile) -> Result<(), Box<dyn Error>>,
+}
+
+/// Every layout change, in order; the step upgrading version N sits at index N
+/// To change the layout, bump `CURRENT_VERSION` and append a step here
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    Migration {
+        from: 0,
+        description: "record device ids and write file ids as device:inode",
+        apply: TagsFile::migrate_device_ids,
+    },
+];
+
+/// The steps needed to bring a database at `version` up to date
+fn pending_migrations(version: u32) -> Result<&'static [Migration], Box<dyn Error>> {
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "database is version {}, but this prlents only understands up to version {}; upgrade prlents",
+            version, CURRENT_VERSION
+        ).into());
+    }
+    Ok(&MIGRATIONS[version as usize..])
+}
+
+/// Upgrade `tags_file` to the current layout one version at a time
+/// Returns the descriptions of the steps that were applied
+pub fn migrate(tags_file: &mut TagsFile) -> Result<Vec<&'static str>, Box<dyn Error>> {
+    let mut applied = Vec::new();
+
+    for migration in pending_migrations(tags_file.version)? {
+        (migration.apply)(tags_file)?;
+        tags_file.version = migration.from + 1;
+        applied.push(migration.description);
+    }
+
+    Ok(applied)
+}
+
+/// Upgrade the database in `store`, listing every change each step makes
+/// With `dry_run` the changes are only shown and the database is left as it is
+pub fn migrate_command(store: &dyn TagStore, dry_run: bool) -> Result<(), Box<dyn Error>> {
+    if !store.exists() {
+        println!("nothing to migrate: {} does not exist", store.describe());
+        return Ok(());
+    }
+
+    let mut tags_file = store.read()?;
+    let migrations = pending_migrations(tags_file.version)?;
+
+    if migrations.is_empty() {
+        println!("{} is already at version {}", store.describe(), CURRENT_VERSION);
+        return Ok(());
+    }
+
+    for migration in migrations {
+        let before = serde_json::to_value(&tags_file)?;
+        (migration.apply)(&mut tags_file)?;
+        tags_file.version = migration.from + 1;
+        let after = serde_json::to_value(&tags_file)?;
+
+        println!("version {} -> {}: {}", migration.from, tags_file.version, migration.description);
+
+        let mut changes = Vec::new();
+        describe_changes("", &before, &after, &mut changes);
+        for change in changes {
+            println!("\t{}", change);
+        }
+    }
+
+    if dry_run {
+        println!("dry run: {} was not changed", store.describe());
+    } else {
+        store.save(&tags_file)?;
+        println!("migrated {} to version {}", store.describe(), CURRENT_VERSION);
+    }
+
+    Ok(())
+}
+
+/// Collect a line for every value that differs between `before` and `after`, e.g. `files[0].device_id: 0 -> 2049`
+fn describe_changes(path: &str, before: &Value, after: &Value, changes: &mut Vec<String>) {
+    match (before, after) {
+        (Value::Object(before_fields), Value::Object(after_fields)) => {
+            let keys = before_fields.keys().chain(after_fields.keys().filter(|key| !before_fields.contains_key(*key)));
+
+            for key in keys {
+                let field_path = if path.is_empty() { key.clone() } else { format!("{}.{}", path, key) };
+
+                match (before_fields.get(key), after_fields.get(key)) {
+                    (Some(old), Some(new)) => describe_changes(&field_path, old, new, changes),
+                    (Some(old), None) => changes.push(format!("{}: removed {}", field_path, old)),
+                    (None, Some(new)) => changes.push(format!("{}: added {}", field_path, new)),
+                    (None, None) => {},
+                }
+            }
+        },
+        (Value::Array(before_items), Value::Array(after_items)) if before_items.len() == after_items.len() => {
+            for (index, (old, new)) in before_items.iter().zip(after_items).enumerate() {
+                describe_changes(&format!("{}[{}]", path, index), old, new, changes);
+            }
+        },
+        _ if before != after => changes.push(format!("{}: {} -> {}", path, before, after)),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrations_cover_every_version() {
+        for (index, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.from as usize, index);
+        }
+    }
+
+    #[test]
+    fn test_migrate_unversioned_database() {
+        let json = r#"{
+            "files": [{"last_known_name": "missing.txt", "file_inode": 42, "parent_dir_inode": 1}],
+            "aliases": {},
+            "tags": [{"name": "a", "type": "normal", "children": [], "ancestry": [], "show": true, "files": ["42"]}]
+        }"#;
+        let mut tags_file: TagsFile = serde_json::from_str(json).unwrap();
+        assert_eq!(tags_file.version, 0);
+
+        let applied = migrate(&mut tags_file).unwrap();
+
+        assert_eq!(applied.len(), 1);
+        assert_eq!(tags_file.version, CURRENT_VERSION);
+        let device_id = tags_file.files[0].device_id;
+        assert_ne!(device_id, 0);
+        assert_eq!(tags_file.tags[0].files, Some(vec![format!("{}:42", device_id)]));
+
+        // Already current, nothing more to do
+        assert!(migrate(&mut tags_file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_reject_newer_database() {
+        let mut tags_file = TagsFile {
+            version: CURRENT_VERSION + 1,
+            ..TagsFile::default()
+        };
+
+        assert!(migrate(&mut tags_file).is_err());
+    }
+}
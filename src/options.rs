@@ -1,5 +1,8 @@
 use argh::FromArgs;
 
+use crate::parser::DEFAULT_INDENT_WIDTH;
+use crate::relationship::DEFAULT_MIN_SCORE;
+
 #[derive(FromArgs)]
 /// prlents - a tool for parsing and filtering
 pub struct Args {
@@ -19,6 +22,46 @@ pub struct Args {
     #[argh(switch, short = 'q', long = "quiet")]
     pub quiet: bool,
 
+    /// only consider files with this extension (repeatable)
+    #[argh(option, long = "ext")]
+    pub ext: Vec<String>,
+
+    /// only accept paths that exist instead of searching the tree for a file by name
+    #[argh(switch, long = "strict")]
+    pub strict: bool,
+
+    /// also skip files that .gitignore files ignore when searching the tree
+    #[argh(switch, long = "gitignore")]
+    pub gitignore: bool,
+
+    /// spaces per indentation level in .ents files; a tab is always one level
+    #[argh(option, long = "indent-width", default = "DEFAULT_INDENT_WIDTH")]
+    pub indent_width: usize,
+
+    /// with relink, move each missing file's tags to its best candidate
+    #[argh(switch, long = "apply")]
+    pub apply: bool,
+
+    /// with relink --apply, the similarity (0-100) a candidate needs before it takes over
+    #[argh(option, long = "min-score", default = "DEFAULT_MIN_SCORE")]
+    pub min_score: u32,
+
+    /// tag database to use; a .bson extension selects the BSON format
+    #[argh(option, long = "db")]
+    pub db: Option<String>,
+
+    /// use the user-level database shared across every registered root
+    #[argh(switch, short = 'g', long = "global")]
+    pub global: bool,
+
+    /// show what a command would change without saving it
+    #[argh(switch, long = "dry-run")]
+    pub dry_run: bool,
+
+    /// seconds to wait for another prlents process to release the database
+    #[argh(option, long = "lock-timeout", default = "10")]
+    pub lock_timeout: u64,
+
     /// command to run
     #[argh(positional)]
     pub command: String,
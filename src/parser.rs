@@ -2,16 +2,17 @@ use nom::{
     IResult,
     branch::alt,
     bytes::complete::{tag, take_while, take_while1, is_not},
-    character::complete::char,
+    character::complete::{char, not_line_ending},
     combinator::{opt, map, eof},
     sequence::{preceded, delimited, tuple},
 };
-use std::collections::HashMap;
+use std::collections::{hash_map, HashMap};
 use std::error::Error;
 use std::fs;
 
 // Import the unified types from common.rs
 use crate::common::{TagType, EntsTag, TagsFile};
+use crate::diagnostic::{Diagnostic, Diagnostics};
 
 /// Represents a parsed tag line with all its components
 /// This is an intermediate structure used during parsing before converting to EntsTag
@@ -21,6 +22,10 @@ struct ParsedTag {
     tag_type: TagType,  // Normal (-), Dud (+), or Exclusive (*)
     name: String,       // The tag name
     alias: Option<String>, // Optional alias in parentheses
+    description: Option<String>, // Comments written above the tag and after it on its line
+    line: usize,        // Line number in the file, filled in by parse_ents_file
+    name_span: (usize, usize),  // Byte offset and length of the name within its line
+    alias_span: Option<(usize, usize)>, // Byte offset and length of the alias, parentheses included
 }
 
 /// Parse tag type markers: -, +, *
@@ -47,15 +52,47 @@ fn parse_spaces1(input: &str) -> IResult<&str, &str> {
     take_while1(|c| c == ' ')(input)
 }
 
-/// Parse indentation and return the indentation level
-/// ENTS requires indentation to be multiples of 4 spaces
-/// Returns the number of indentation levels (spaces / 4)
-fn parse_indent(input: &str) -> IResult<&str, usize> {
-    map(parse_spaces, |spaces| spaces.len())(input)
+/// Spaces per indentation level unless another width is asked for
+pub const DEFAULT_INDENT_WIDTH: usize = 4;
+
+/// Indentation as written at the start of a line
+#[derive(Debug, Clone, Copy)]
+struct Indent {
+    spaces: usize,
+    tabs: usize,
+}
+
+impl Indent {
+    /// Length of the indentation in bytes
+    fn len(&self) -> usize {
+        self.spaces + self.tabs
+    }
+
+    /// The indentation level, or why the indentation is invalid
+    /// ENTS indents with either tabs, one per level, or spaces in multiples of `indent_width`
+    fn level(&self, indent_width: usize) -> Result<usize, String> {
+        if self.tabs > 0 && self.spaces > 0 {
+            Err("indentation mixes tabs and spaces".to_string())
+        } else if self.tabs > 0 {
+            Ok(self.tabs)
+        } else if !self.spaces.is_multiple_of(indent_width) {
+            Err(format!("indentation of {} spaces is not a multiple of {}", self.spaces, indent_width))
+        } else {
+            Ok(self.spaces / indent_width)
+        }
+    }
+}
+
+/// Parse indentation made of spaces and tabs, counting each
+fn parse_indent(input: &str) -> IResult<&str, Indent> {
+    map(take_while(|c| c == ' ' || c == '\t'), |indent: &str| Indent {
+        spaces: indent.chars().filter(|c| *c == ' ').count(),
+        tabs: indent.chars().filter(|c| *c == '\t').count(),
+    })(input)
 }
 
 /// Parse an escaped character for tag names
-/// Allows escaping of special characters like (, ), and :
+/// Allows escaping of special characters like (, ), :, and #
 /// Returns a parser that matches \c where c is the specified character
 fn parse_escaped_char(c: char) -> impl Fn(&str) -> IResult<&str, char> {
     move |input: &str| {
@@ -64,7 +101,7 @@ fn parse_escaped_char(c: char) -> impl Fn(&str) -> IResult<&str, char> {
 }
 
 /// Parse a tag name with support for escaped characters
-/// Tag names continue until they hit a terminator: (, :, newline, or end of input
+/// Tag names continue until they hit a terminator: (, :, #, newline, or end of input
 /// Supports escaping of terminators with backslashes
 /// Returns the trimmed tag name
 fn parse_tag_name(input: &str) -> IResult<&str, String> {
@@ -73,11 +110,12 @@ fn parse_tag_name(input: &str) -> IResult<&str, String> {
     
     loop {
         // Try to parse escaped characters first
-        // This allows tag names to contain literal (, ), or : characters
+        // This allows tag names to contain literal (, ), :, or # characters
         if let Ok((rest, ch)) = alt((
             parse_escaped_char('('),
             parse_escaped_char(')'),
             parse_escaped_char(':'),
+            parse_escaped_char('#'),
         ))(remaining) {
             result.push(ch);
             remaining = rest;
@@ -88,6 +126,7 @@ fn parse_tag_name(input: &str) -> IResult<&str, String> {
         if remaining.is_empty() || 
            remaining.starts_with('(') ||   // Start of alias
            remaining.starts_with(':') ||   // End of line marker (optional)
+           remaining.starts_with('#') ||   // Start of a trailing comment
            remaining.starts_with('\n') ||  // Newline
            remaining.starts_with('\r') {   // Carriage return
             break;
@@ -126,37 +165,56 @@ fn parse_alias(input: &str) -> IResult<&str, String> {
     )(input)
 }
 
+/// Parse a comment, running from # to the end of the line
+/// Returns the comment text without the marker and the space after it
+fn parse_comment(input: &str) -> IResult<&str, String> {
+    map(
+        preceded(char('#'), not_line_ending),
+        |text: &str| text.strip_prefix(' ').unwrap_or(text).trim_end().to_string()
+    )(input)
+}
+
+/// Parse a line holding nothing but a comment, indented or not
+fn parse_comment_line(input: &str) -> IResult<&str, String> {
+    preceded(parse_indent, parse_comment)(input)
+}
+
 /// Parse a complete tag line
-/// Format: [indent][tag_type] [tag_name][ (alias)][ :]
+/// Format: [indent][tag_type] [tag_name][ (alias)][ :][ # comment]
 /// Where:
-/// - indent is 0 or more groups of 4 spaces
+/// - indent is 0 or more tabs, or 0 or more groups of `indent_width` spaces
 /// - tag_type is -, +, or *
 /// - tag_name is required and can contain escaped characters
 /// - alias is optional and enclosed in parentheses
 /// - : is optional and marks end of line explicitly
-fn parse_tag_line(input: &str) -> IResult<&str, ParsedTag> {
-    let original_input = input;
-    
-    // Parse indentation (must be multiple of 4 spaces)
+/// - a trailing comment is optional and becomes the tag's description
+fn parse_tag_line(input: &str, indent_width: usize) -> IResult<&str, ParsedTag> {
+    let line = input;
+    let offset = |rest: &str| line.len() - rest.len();
+
+    // Parse indentation (tabs, or a multiple of indent_width spaces)
     let (input, indent) = parse_indent(input)?;
     
-    // Verify indent is multiple of 4 for proper ENTS formatting
-    if indent % 4 != 0 {
-        println!("Invalid indent: {} spaces", indent);
-        return Err(nom::Err::Error(nom::error::Error::new(
+    // Verify the indent for proper ENTS formatting
+    // The reason a line fails is worked out afterwards by `explain_line`
+    let indent = match indent.level(indent_width) {
+        Ok(level) => level,
+        Err(_) => return Err(nom::Err::Error(nom::error::Error::new(
             input, 
             nom::error::ErrorKind::Verify
-        )));
-    }
+        ))),
+    };
     
     // Parse the tag type marker (-, +, or *)
     let (input, tag_type) = parse_tag_type(input)?;
     
     // Require at least one space after tag type
-    let (input, spaces) = parse_spaces1(input)?;
+    let (input, _) = parse_spaces1(input)?;
     
     // Parse the tag name
+    let name_start = offset(input);
     let (input, name) = parse_tag_name(input)?;
+    let name_span = (name_start, line[name_start..offset(input)].trim_end().len());
     
     // After the tag name, we might have:
     // 1. Nothing (end of line)
@@ -168,7 +226,9 @@ fn parse_tag_line(input: &str) -> IResult<&str, ParsedTag> {
     let (input, _) = parse_spaces(input)?;
     
     // Parse optional alias in parentheses
+    let alias_start = offset(input);
     let (input, alias) = opt(parse_alias)(input)?;
+    let alias_span = alias.as_ref().map(|_| (alias_start, offset(input) - alias_start));
     
     // Parse optional trailing spaces
     let (input, _) = parse_spaces(input)?;
@@ -178,12 +238,19 @@ fn parse_tag_line(input: &str) -> IResult<&str, ParsedTag> {
     
     // Parse any final trailing spaces
     let (input, _) = parse_spaces(input)?;
+
+    // Parse an optional trailing comment
+    let (input, description) = opt(parse_comment)(input)?;
     
     Ok((input, ParsedTag {
-        indent: indent / 4, // Convert to indentation level (0, 1, 2, etc.)
+        indent,
         tag_type,
         name,
         alias,
+        description,
+        line: 0,
+        name_span,
+        alias_span,
     }))
 }
 
@@ -206,7 +273,7 @@ fn parse_line(input: &str) -> IResult<&str, Option<ParsedTag>> {
         // Tag line followed by newline or EOF
         map(
             tuple((
-                parse_tag_line,
+                |input| parse_tag_line(input, DEFAULT_INDENT_WIDTH),
                 alt((
                     map(parse_newline, |_| ()),
                     map(eof, |_| ()),
@@ -217,80 +284,220 @@ fn parse_line(input: &str) -> IResult<&str, Option<ParsedTag>> {
     ))(input)
 }
 
+/// Work out where and why a line that is neither a tag nor a comment fails to parse
+/// Steps through the parts of a tag line in order and reports the first one that is wrong,
+/// as the byte offset and length of the offending text and an explanation
+fn explain_line(line: &str, indent_width: usize) -> (usize, usize, String) {
+    let offset = |rest: &str| line.len() - rest.len();
+
+    let (rest, indent) = match parse_indent(line) {
+        Ok(parsed) => parsed,
+        Err(_) => return (0, line.len(), "could not read this line".to_string()),
+    };
+    if let Err(message) = indent.level(indent_width) {
+        return (0, indent.len(), message);
+    }
+
+    let rest = match parse_tag_type(rest) {
+        Ok((rest, _)) => rest,
+        Err(_) => {
+            let found = rest.chars().next().map_or(1, char::len_utf8);
+            return (offset(rest), found, "expected a tag marker: - for a normal tag, + for a dud, * for an exclusive tag".to_string());
+        }
+    };
+
+    let rest = match parse_spaces1(rest) {
+        Ok((rest, _)) => rest,
+        Err(_) => return (offset(rest), 1, "expected a space after the tag marker".to_string()),
+    };
+
+    let rest = match parse_tag_name(rest) {
+        Ok((rest, _)) => rest,
+        Err(_) => return (offset(rest), rest.len(), "missing tag name".to_string()),
+    };
+
+    let (rest, _) = parse_spaces(rest).unwrap_or((rest, ""));
+    if rest.starts_with('(') {
+        if !rest.contains(')') {
+            return (offset(rest), rest.len(), "alias is missing its closing parenthesis".to_string());
+        }
+        if parse_alias(rest).is_err() {
+            return (offset(rest), 2, "alias is empty".to_string());
+        }
+    }
+
+    // Whatever comes after a complete tag line; find where the parser stopped
+    let rest = parse_tag_line(line, indent_width).map_or(rest, |(rest, _)| rest);
+    (offset(rest), rest.trim_end().len(), "unexpected text after the tag; escape (, ), : and # in tag names with a backslash".to_string())
+}
+
 /// Parse an entire ENTS file
 /// Processes the file line by line, tracking line numbers for error reporting
-/// Skips empty lines and parses tag lines
-fn parse_ents_file(input: &str) -> IResult<&str, Vec<ParsedTag>> {
-    let mut remaining = input;
+/// Skips empty lines and comment lines, parses tag lines, and attaches the comment
+/// lines directly above a tag to it as its description; a blank line detaches them
+/// Returns the tags that parsed along with a diagnostic for every line that did not
+fn parse_ents_file(input: &str, file_path: &str, indent_width: usize) -> (Vec<ParsedTag>, Vec<Diagnostic>) {
     let mut tags = Vec::new();
-    let mut line_num = 1;
+    let mut comments: Vec<String> = Vec::new();
+    let mut diagnostics = Vec::new();
     
-    // Process input character by character until we've consumed everything
-    while !remaining.is_empty() {
-        // Skip any empty lines at the beginning
-        while remaining.starts_with('\n') || remaining.starts_with('\r') {
-            if remaining.starts_with("\r\n") {
-                remaining = &remaining[2..];
-                line_num += 1;
-            } else if remaining.starts_with('\n') || remaining.starts_with('\r') {
-                remaining = &remaining[1..];
-                line_num += 1;
-            }
+    for (index, line) in input.lines().enumerate() {
+        let line_num = index + 1;
+
+        if line.trim().is_empty() {
+            comments.clear();
+            continue;
         }
-        
-        // If we've consumed all input, we're done
-        if remaining.is_empty() {
-            break;
+
+        if let Ok(("", comment)) = parse_comment_line(line) {
+            comments.push(comment);
+            continue;
         }
         
-        // Try to parse a tag line
-        match parse_tag_line(remaining) {
-            Ok((rest, tag)) => {
-                // Successfully parsed a tag
-                tags.push(tag);
-                remaining = rest;
-                
-                // Consume the line ending after the tag
-                if remaining.starts_with("\r\n") {
-                    remaining = &remaining[2..];
-                    line_num += 1;
-                } else if remaining.starts_with('\n') || remaining.starts_with('\r') {
-                    remaining = &remaining[1..];
-                    line_num += 1;
+        // Try to parse a tag line, which must take up the whole line
+        match parse_tag_line(line, indent_width) {
+            Ok(("", mut tag)) => {
+                tag.line = line_num;
+                comments.extend(tag.description.take());
+                if !comments.is_empty() {
+                    tag.description = Some(comments.join("\n"));
+                    comments.clear();
                 }
+                tags.push(tag);
             }
-            Err(e) => {
-                // If we can't parse a line and there's non-whitespace content left, that's an error
-                if !remaining.trim().is_empty() {
-                    println!("Failed to parse at line {}", line_num);
-                    println!("Remaining content: {:?}", remaining.lines().next().unwrap_or(""));
-                    return Err(nom::Err::Error(nom::error::Error::new(
-                        remaining,
-                        nom::error::ErrorKind::Many0
-                    )));
-                }
-                break;
+            _ => {
+                let (start, length, message) = explain_line(line, indent_width);
+                diagnostics.push(Diagnostic::new(file_path, line_num, line, start, length, message));
+                comments.clear();
             }
         }
     }
     
-    Ok(("", tags))
+    (tags, diagnostics)
+}
+
+/// Parse an entire ENTS file and check the tags that parsed, reporting every problem in one pass
+/// The indentation must say unambiguously where each tag belongs, and every name and
+/// alias must lead to a single tag, or lookups would silently pick one
+fn parse_and_validate(input: &str, file_path: &str, indent_width: usize) -> Result<Vec<ParsedTag>, Vec<Diagnostic>> {
+    let (parsed_tags, mut diagnostics) = parse_ents_file(input, file_path, indent_width);
+
+    let lines: Vec<&str> = input.lines().collect();
+    diagnostics.extend(validate_indentation(&parsed_tags, &lines, file_path));
+    diagnostics.extend(validate_names(&parsed_tags, &lines, file_path));
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+
+    if diagnostics.is_empty() {
+        Ok(parsed_tags)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// Check that every tag path and alias in the file refers to exactly one tag
+/// The same leaf name may appear in different branches, since tags are told apart by path;
+/// reports tags defined twice at the same path, names containing the path separator,
+/// aliases that are also the name of another tag, and aliases given to more than one tag,
+/// each pointing at the later definition
+fn validate_names(parsed_tags: &[ParsedTag], lines: &[&str], file_path: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let report = |tag: &ParsedTag, (start, length): (usize, usize), message: String| {
+        Diagnostic::new(file_path, tag.line, lines[tag.line - 1], start, length, message)
+    };
+
+    // The path of each tag, worked out from the indentation the same way as in build_hierarchy
+    let mut stack: Vec<&str> = Vec::new();
+    let paths: Vec<String> = parsed_tags.iter()
+        .map(|tag| {
+            stack.truncate(tag.indent);
+            stack.push(&tag.name);
+            stack.join("/")
+        })
+        .collect();
+
+    let mut tags_by_path: HashMap<&str, usize> = HashMap::new();
+    let mut tags_by_name: HashMap<&str, usize> = HashMap::new();
+    for (index, tag) in parsed_tags.iter().enumerate() {
+        if tag.name.contains('/') {
+            diagnostics.push(report(tag, tag.name_span,
+                "tag names cannot contain '/', which separates the parts of a tag path".to_string()));
+        }
+
+        match tags_by_path.entry(&paths[index]) {
+            hash_map::Entry::Occupied(first) => diagnostics.push(report(tag, tag.name_span,
+                format!("tag '{}' is already defined on line {}", paths[index], parsed_tags[*first.get()].line))),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(index);
+            },
+        }
+        tags_by_name.entry(&tag.name).or_insert(index);
+    }
+
+    let mut tags_by_alias: HashMap<&str, usize> = HashMap::new();
+    for (index, tag) in parsed_tags.iter().enumerate() {
+        let (alias, span) = match (&tag.alias, tag.alias_span) {
+            (Some(alias), Some(span)) => (alias.as_str(), span),
+            _ => continue,
+        };
+
+        let named = tags_by_path.get(alias).or_else(|| tags_by_name.get(alias));
+        if let Some(&named) = named.filter(|named| paths[**named] != paths[index]) {
+            diagnostics.push(report(tag, span,
+                format!("alias '{}' is the name of the tag on line {}", alias, parsed_tags[named].line)));
+        }
+
+        match tags_by_alias.entry(alias) {
+            hash_map::Entry::Occupied(first) if paths[*first.get()] != paths[index] => diagnostics.push(report(tag, span,
+                format!("alias '{}' already stands for '{}' on line {}", alias, paths[*first.get()], parsed_tags[*first.get()].line))),
+            hash_map::Entry::Occupied(_) => {},
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(index);
+            },
+        }
+    }
+
+    diagnostics.sort_by_key(|diagnostic| (diagnostic.line, diagnostic.column));
+    diagnostics
+}
+
+/// Check that each tag is indented at most one level deeper than the tag above it
+/// A deeper jump leaves it unclear which tag is meant to be its parent
+/// A tag right below a line that failed to parse is not checked, since that line may have been its parent
+fn validate_indentation(parsed_tags: &[ParsedTag], lines: &[&str], file_path: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut previous_level: Option<usize> = None;
+    let mut previous_line = 0;
+
+    for tag in parsed_tags {
+        let deepest = previous_level.map_or(0, |level| level + 1);
+        let follows_bad_line = lines[previous_line..tag.line - 1].iter()
+            .any(|line| !line.trim().is_empty() && !matches!(parse_comment_line(line), Ok(("", _))));
+        if tag.indent > deepest && !follows_bad_line {
+            let source = lines[tag.line - 1];
+            let indent_length = source.len() - source.trim_start_matches([' ', '\t']).len();
+            let message = match previous_level {
+                Some(level) => format!("tag is indented {} levels but the tag above is at level {}; indent children one level deeper than their parent",
+                    tag.indent, level),
+                None => format!("the first tag is indented {} levels; top-level tags are not indented", tag.indent),
+            };
+            diagnostics.push(Diagnostic::new(file_path, tag.line, source, 0, indent_length, message));
+        }
+        previous_level = Some(tag.indent);
+        previous_line = tag.line;
+    }
+
+    diagnostics
 }
 
 /// Build a hierarchical tag structure from flat parsed tags
 /// Creates parent-child relationships based on indentation levels
-/// Also extracts aliases and creates a mapping from alias to tag name
+/// Also extracts aliases and creates a mapping from alias to tag path
 fn build_hierarchy(parsed_tags: Vec<ParsedTag>) -> (Vec<EntsTag>, HashMap<String, String>) {
     let mut aliases = HashMap::new();
     let mut all_tags: Vec<EntsTag> = Vec::new();
     let mut tag_stack: Vec<usize> = Vec::new(); // Stack of indices into all_tags for tracking hierarchy
     
     for parsed_tag in parsed_tags {
-        // Add alias to the aliases map if present
-        if let Some(alias) = &parsed_tag.alias {
-            aliases.insert(alias.clone(), parsed_tag.name.clone());
-        }
-        
         // Adjust stack to match current indent level
         // Remove tags from stack that are at the same or deeper level
         tag_stack.truncate(parsed_tag.indent);
@@ -302,17 +509,25 @@ fn build_hierarchy(parsed_tags: Vec<ParsedTag>) -> (Vec<EntsTag>, HashMap<String
         }
         
         // Create the new tag with the calculated ancestry
-        let mut tag = EntsTag {
+        let tag = EntsTag {
             name: parsed_tag.name.clone(),
             tag_type: parsed_tag.tag_type,
             children: Vec::new(),     // Will be populated as we process children
             ancestry,
             show: Some(true),         // New tags are visible by default
             files: None,              // Set to None to match expected JSON output
+            assigned: HashMap::new(),
+            description: parsed_tag.description,
             child_tags: Vec::new(),   // Temporary field used during parsing
             alias: parsed_tag.alias,
         };
         
+        // Add alias to the aliases map if present, pointing at the tag's path
+        // so it stays unambiguous when the leaf name repeats in another branch
+        if let Some(alias) = &tag.alias {
+            aliases.insert(alias.clone(), tag.path());
+        }
+        
         // Add this tag to its parent's children list if there is a parent
         if let Some(&parent_idx) = tag_stack.last() {
             all_tags[parent_idx].children.push(parsed_tag.name.clone());
@@ -335,11 +550,16 @@ fn build_hierarchy(parsed_tags: Vec<ParsedTag>) -> (Vec<EntsTag>, HashMap<String
 /// 
 /// # Arguments
 /// * `file_path` - Path to the ENTS file to parse
+/// * `indent_width` - Spaces per indentation level; tabs always count as one level
 /// 
 /// # Returns
 /// * `Ok(TagsFile)` - Successfully parsed tag structure
-/// * `Err(Box<dyn Error>)` - Parse error or file I/O error
-pub fn parse_ents(file_path: &str) -> Result<TagsFile, Box<dyn Error>> {
+/// * `Err(Box<dyn Error>)` - `Diagnostics` for every line that could not be parsed, or a file I/O error
+pub fn parse_ents(file_path: &str, indent_width: usize) -> Result<TagsFile, Box<dyn Error>> {
+    if indent_width == 0 {
+        return Err("indent width must be at least 1".into());
+    }
+
     // Read the file contents
     let content = fs::read_to_string(file_path)?;
     
@@ -348,13 +568,7 @@ pub fn parse_ents(file_path: &str) -> Result<TagsFile, Box<dyn Error>> {
     let normalized_content = content.replace("\r\n", "\n").replace("\r", "\n");
     
     // Parse the normalized content
-    let (remaining, parsed_tags) = parse_ents_file(&normalized_content)
-        .map_err(|e| format!("Parse error: {:?}", e))?;
-    
-    // Check if we parsed the entire file successfully
-    if !remaining.trim().is_empty() {
-        return Err(format!("Failed to parse entire file. Remaining: {:?}", remaining).into());
-    }
+    let parsed_tags = parse_and_validate(&normalized_content, file_path, indent_width).map_err(Diagnostics)?;
     
     println!("Parsed {} tags", parsed_tags.len());
     
@@ -366,6 +580,7 @@ pub fn parse_ents(file_path: &str) -> Result<TagsFile, Box<dyn Error>> {
         files: Vec::new(), // Initialize with empty files vector
         aliases,
         tags: all_tags,
+        ..TagsFile::default()
     })
 }
 
@@ -402,7 +617,7 @@ mod tests {
     #[test]
     fn test_parse_simple_tag() {
         let input = "- jade\n";
-        let (_, tag) = parse_tag_line(input).unwrap();
+        let (_, tag) = parse_tag_line(input, DEFAULT_INDENT_WIDTH).unwrap();
         assert_eq!(tag.indent, 0);
         assert_eq!(tag.tag_type, TagType::Normal);
         assert_eq!(tag.name, "jade");
@@ -413,10 +628,97 @@ mod tests {
     #[test]
     fn test_parse_tag_with_alias() {
         let input = "    * new york (ny)\n"; // Updated to use * instead of +-
-        let (_, tag) = parse_tag_line(input).unwrap();
+        let (_, tag) = parse_tag_line(input, DEFAULT_INDENT_WIDTH).unwrap();
         assert_eq!(tag.indent, 1);
         assert_eq!(tag.tag_type, TagType::Exclusive);
         assert_eq!(tag.name, "new york");
         assert_eq!(tag.alias, Some("ny".to_string()));
     }
+
+    /// Test that comments are skipped and kept as descriptions of the tag that follows
+    #[test]
+    fn test_parse_comments() {
+        let input = "# places we have lived\n- places\n\n# detached by the blank line\n\n    # the big apple\n    - new york (ny) # since 2019\n    - c\\#sharp city\n";
+        let tags = parse_and_validate(input, "tags.ents", DEFAULT_INDENT_WIDTH).unwrap();
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags[0].description.as_deref(), Some("places we have lived"));
+        assert_eq!(tags[1].name, "new york");
+        assert_eq!(tags[1].alias, Some("ny".to_string()));
+        assert_eq!(tags[1].description.as_deref(), Some("the big apple\nsince 2019"));
+        assert_eq!(tags[2].name, "c#sharp city");
+        assert_eq!(tags[2].description, None);
+    }
+
+    /// Test that every bad line is reported with the column where it goes wrong
+    #[test]
+    fn test_parse_errors_are_all_reported() {
+        let input = "- ok\n  - two spaces\n= wrong marker\n-nospace\n- tag (alias\n- tag (alias) extra\n";
+        let diagnostics = parse_and_validate(input, "tags.ents", DEFAULT_INDENT_WIDTH).unwrap_err();
+        let found: Vec<(usize, usize, &str)> = diagnostics.iter()
+            .map(|d| (d.line, d.column, d.message.split(&[';', ':'][..]).next().unwrap()))
+            .collect();
+        assert_eq!(found, vec![
+            (2, 1, "indentation of 2 spaces is not a multiple of 4"),
+            (3, 1, "expected a tag marker"),
+            (4, 2, "expected a space after the tag marker"),
+            (5, 7, "alias is missing its closing parenthesis"),
+            (6, 15, "unexpected text after the tag"),
+        ]);
+    }
+
+    /// Test that repeated names and colliding aliases are reported at the later definition
+    #[test]
+    fn test_validate_names() {
+        let input = "- places (p)\n    - paris (pa)\n- people (pa)\n    - paris\n- pets (places)\n- plans (p)\n- places\n    - paris\n- a/b\n";
+        let (tags, _) = parse_ents_file(input, "tags.ents", DEFAULT_INDENT_WIDTH);
+        let lines: Vec<&str> = input.lines().collect();
+        let found: Vec<(usize, usize, String)> = validate_names(&tags, &lines, "tags.ents").into_iter()
+            .map(|d| (d.line, d.column, d.message))
+            .collect();
+        assert_eq!(found, vec![
+            (3, 10, "alias 'pa' already stands for 'places/paris' on line 2".to_string()),
+            (5, 8, "alias 'places' is the name of the tag on line 1".to_string()),
+            (6, 9, "alias 'p' already stands for 'places' on line 1".to_string()),
+            (7, 3, "tag 'places' is already defined on line 1".to_string()),
+            (8, 7, "tag 'places/paris' is already defined on line 2".to_string()),
+            (9, 3, "tag names cannot contain '/', which separates the parts of a tag path".to_string()),
+        ]);
+
+        // A repeated leaf in another branch is its own tag, and aliases point at paths
+        let input = "- invoices\n    - 2023 (inv23)\n- photos\n    - 2023\n";
+        let (tags, _) = parse_ents_file(input, "tags.ents", DEFAULT_INDENT_WIDTH);
+        let lines: Vec<&str> = input.lines().collect();
+        assert!(validate_names(&tags, &lines, "tags.ents").is_empty());
+        let (all_tags, aliases) = build_hierarchy(tags);
+        assert_eq!(all_tags[3].path(), "photos/2023");
+        assert_eq!(aliases.get("inv23"), Some(&"invoices/2023".to_string()));
+    }
+
+    /// Test tab indentation, a custom indent width, and rejection of indentation jumps
+    #[test]
+    fn test_indentation() {
+        let tags = parse_and_validate("- a\n\t- b\n\t\t# about c\n\t\t- c\n", "tags.ents", 2).unwrap();
+        assert_eq!(tags.iter().map(|t| t.indent).collect::<Vec<_>>(), vec![0, 1, 2]);
+
+        let input = "- a\n  - b\n      - c\n\t  - d\n";
+        let (_, diagnostics) = parse_ents_file(input, "tags.ents", 2);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!((diagnostics[0].line, diagnostics[0].message.as_str()), (4, "indentation mixes tabs and spaces"));
+
+        let (tags, _) = parse_ents_file("    - a\n- b\n        - c\n", "tags.ents", 4);
+        let lines = vec!["    - a", "- b", "        - c"];
+        let found: Vec<(usize, usize, usize)> = validate_indentation(&tags, &lines, "tags.ents").iter()
+            .map(|d| (d.line, d.column, d.length))
+            .collect();
+        assert_eq!(found, vec![(1, 1, 4), (3, 1, 8)]);
+    }
+
+    /// Test that a syntax error does not hide the duplicate and indentation errors around it
+    #[test]
+    fn test_syntax_and_validation_errors_together() {
+        let input = "- a\n-bad\n- a\n    = bad child\n        - c\n- d\n        - e\n";
+        let diagnostics = parse_and_validate(input, "tags.ents", DEFAULT_INDENT_WIDTH).unwrap_err();
+        let found: Vec<usize> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(found, vec![2, 3, 4, 7]);
+    }
 }
@@ -0,0 +1,375 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use serde::{Serialize, Deserialize};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::common::FileId;
+use crate::storage::{is_database_file, write_atomically, DATABASE_NAMES};
+
+/// Name of the gitignore-style file listing paths that walks skip
+const IGNORE_FILE: &str = ".entsignore";
+
+/// Listing of the directories under the tracked roots, kept as JSON in `<database>.paths`
+/// Name and inode lookups read the listing instead of walking the tree; `refresh` brings it
+/// up to date by re-reading only the directories whose modification time has changed
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PathCache {
+    // Every directory seen, by its path from the root it was walked from
+    dirs: HashMap<String, CachedDir>,
+
+    // Where the cache is saved; None for a cache that only lives in memory
+    #[serde(skip)]
+    path: Option<PathBuf>,
+
+    // The database the cache belongs to, whose own files are never the target of a lookup
+    #[serde(skip)]
+    database: Option<PathBuf>,
+
+    // Set when a refresh changed the listing, skipped during serialization
+    #[serde(skip)]
+    dirty: bool,
+
+    // Rules read from the ignore files in the listed directories
+    #[serde(skip)]
+    ignore: IgnoreRules,
+}
+
+/// Gitignore-style rules from every `.entsignore`, and optionally `.gitignore`, in the listed tree
+/// Each file applies to the directory holding it and everything below; deeper files take precedence
+#[derive(Debug, Clone, Default)]
+struct IgnoreRules {
+    gitignore: bool,
+    by_dir: HashMap<String, Gitignore>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedDir {
+    // Modification time of the directory when it was listed
+    // Creating, removing or renaming an entry changes it, so an unchanged time means an unchanged listing
+    mtime: i64,
+    mtime_nsec: i64,
+    files: Vec<CachedFile>,
+    subdirs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedFile {
+    name: String,
+    device_id: u64,
+    inode: u64,
+}
+
+impl PathCache {
+    /// The cache that belongs to the database at `database`
+    /// A missing or unreadable cache is simply rebuilt, so read errors start an empty one
+    pub fn beside(database: &Path) -> PathCache {
+        let mut path = database.as_os_str().to_owned();
+        path.push(".paths");
+        let path = PathBuf::from(path);
+
+        let mut cache: PathCache = fs::read(&path).ok()
+            .and_then(|content| serde_json::from_slice(&content).ok())
+            .unwrap_or_default();
+        cache.path = Some(path);
+        cache.database = Some(database.to_path_buf());
+        cache.load_rules();
+        cache
+    }
+
+    /// Whether `path` is one of the files prlents keeps beside the database rather than a tagged file
+    /// Databases are rewritten by rename and backed up by hard link, so their inodes are often
+    /// reused and must never be taken for a tagged file that vanished
+    pub fn is_database_file(&self, path: &str) -> bool {
+        let database = self.database.as_deref().unwrap_or(Path::new(DATABASE_NAMES[0]));
+        Path::new(path).file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| is_database_file(name, database))
+    }
+
+    /// Also skip whatever the `.gitignore` files in the tree ignore
+    pub fn set_gitignore(&mut self, gitignore: bool) {
+        if self.ignore.gitignore != gitignore {
+            self.ignore.gitignore = gitignore;
+            self.load_rules();
+        }
+    }
+
+    /// Read the ignore files of every listed directory again
+    fn load_rules(&mut self) {
+        self.ignore.by_dir.clear();
+        for (dir, listing) in &self.dirs {
+            self.ignore.load(dir, listing);
+        }
+    }
+
+    /// Write the cache back if a refresh changed it
+    /// The write is atomic so a crash or a concurrent reader never sees a truncated listing
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        match &self.path {
+            Some(path) if self.dirty => write_atomically(path, &serde_json::to_vec(self)?, false),
+            _ => Ok(()),
+        }
+    }
+
+    /// Bring the listing of every directory under `roots` up to date
+    /// Directories that vanished are forgotten and changed ones are listed again;
+    /// ignored directories are not descended into and drop out of the listing
+    pub fn refresh(&mut self, roots: &[String]) -> Result<(), Box<dyn Error>> {
+        for root in roots {
+            let root = cache_key(Path::new(root));
+            let mut seen = HashSet::new();
+            let mut pending = vec![root.clone()];
+
+            while let Some(dir) = pending.pop() {
+                let metadata = match fs::metadata(&dir) {
+                    Ok(metadata) if metadata.is_dir() => metadata,
+                    _ => continue,
+                };
+
+                let unchanged = self.dirs.get(&dir)
+                    .is_some_and(|cached| cached.mtime == metadata.mtime() && cached.mtime_nsec == metadata.mtime_nsec());
+                if !unchanged {
+                    let listing = list_dir(&dir, &metadata)?;
+                    self.dirs.insert(dir.clone(), listing);
+                    self.dirty = true;
+                }
+
+                // The ignore file may have been edited even when the listing has not changed
+                let listing = &self.dirs[&dir];
+                self.ignore.load(&dir, listing);
+
+                for subdir in &listing.subdirs {
+                    let subdir = cache_key(&Path::new(&dir).join(subdir));
+                    if !self.ignore.is_ignored(&subdir, true) {
+                        pending.push(subdir);
+                    }
+                }
+                seen.insert(dir);
+            }
+
+            let before = self.dirs.len();
+            self.dirs.retain(|dir, _| seen.contains(dir) || !is_within(dir, &root));
+            self.dirty |= self.dirs.len() != before;
+            self.ignore.by_dir.retain(|dir, _| self.dirs.contains_key(dir));
+        }
+
+        Ok(())
+    }
+
+    /// The listed directories under `roots` that are not ignored
+    fn dirs_under<'a>(&'a self, roots: &[String]) -> impl Iterator<Item = (&'a String, &'a CachedDir)> + 'a {
+        let roots: Vec<String> = roots.iter().map(|root| cache_key(Path::new(root))).collect();
+        self.dirs.iter()
+            .filter(move |(dir, _)| roots.iter().any(|root| is_within(dir, root)))
+            .filter(|(dir, _)| !self.ignore.is_ignored(dir, true))
+    }
+
+    /// Every file listed under `roots` and not ignored, with its id as of the last refresh
+    pub fn files(&self, roots: &[String]) -> Vec<(String, FileId)> {
+        let mut files: Vec<(String, FileId)> = self.dirs_under(roots)
+            .flat_map(|(dir, cached)| cached.files.iter().map(move |file| {
+                let file_id = FileId { device_id: file.device_id, inode: file.inode };
+                (cache_key(&Path::new(dir).join(&file.name)), file_id)
+            }))
+            .filter(|(path, _)| !self.ignore.is_ignored(path, false))
+            .collect();
+        files.sort_by(|a, b| a.0.cmp(&b.0));
+        files
+    }
+
+    /// Every file or directory under `roots` whose name matches `name` regardless of case
+    pub fn paths_named(&self, roots: &[String], name: &str) -> Vec<String> {
+        let mut paths: Vec<String> = self.dirs_under(roots)
+            .flat_map(|(dir, cached)| {
+                let files = cached.files.iter().map(|file| (&file.name, false));
+                let subdirs = cached.subdirs.iter().map(|subdir| (subdir, true));
+                files.chain(subdirs)
+                    .filter(|(entry, _)| entry.eq_ignore_ascii_case(name))
+                    .map(move |(entry, is_dir)| (cache_key(&Path::new(dir).join(entry)), is_dir))
+            })
+            .filter(|(path, is_dir)| !self.ignore.is_ignored(path, *is_dir))
+            .map(|(path, _)| path)
+            .collect();
+        paths.sort();
+        paths
+    }
+}
+
+impl IgnoreRules {
+    /// Take the rules from the ignore files among the entries of `dir`, replacing any read before
+    fn load(&mut self, dir: &str, listing: &CachedDir) {
+        let mut names = vec![IGNORE_FILE];
+        if self.gitignore {
+            names.push(".gitignore");
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        let mut found = false;
+        for name in names {
+            if listing.files.iter().any(|file| file.name == name) {
+                if let Some(e) = builder.add(Path::new(dir).join(name)) {
+                    eprintln!("Warning: ignoring part of {}: {}", Path::new(dir).join(name).display(), e);
+                }
+                found = true;
+            }
+        }
+
+        if !found {
+            self.by_dir.remove(dir);
+            return;
+        }
+
+        match builder.build() {
+            Ok(rules) => {
+                self.by_dir.insert(dir.to_string(), rules);
+            },
+            Err(e) => eprintln!("Warning: cannot read ignore rules in {}: {}", dir, e),
+        }
+    }
+
+    /// Whether `path`, or a directory it is in, is ignored by the rules of any directory above it
+    /// `.git` directories are always skipped
+    fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        if Path::new(path).components().any(|component| component.as_os_str() == ".git") {
+            return true;
+        }
+
+        // The deepest ignore file with an opinion decides, as in git
+        let mut applicable: Vec<(&String, &Gitignore)> = self.by_dir.iter()
+            .filter(|(dir, _)| dir.as_str() != path && is_within(path, dir))
+            .collect();
+        applicable.sort_by_key(|(dir, _)| std::cmp::Reverse(Path::new(dir).components().count()));
+
+        for (_, rules) in applicable {
+            let matched = rules.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+/// Read one directory, recording the id of each regular file and the name of each subdirectory
+/// Symbolic links are not followed, the same as the walks this cache replaces
+fn list_dir(dir: &str, metadata: &fs::Metadata) -> Result<CachedDir, Box<dyn Error>> {
+    let mut listing = CachedDir {
+        mtime: metadata.mtime(),
+        mtime_nsec: metadata.mtime_nsec(),
+        files: Vec::new(),
+        subdirs: Vec::new(),
+    };
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Error during directory traversal: {}", e);
+            return Ok(listing);
+        }
+    };
+
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Error during directory traversal: {}", e);
+                continue;
+            }
+        };
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => listing.subdirs.push(name),
+            Ok(file_type) if file_type.is_file() => match entry.metadata() {
+                Ok(metadata) => listing.files.push(CachedFile { name, device_id: metadata.dev(), inode: metadata.ino() }),
+                Err(e) => eprintln!("Error reading metadata for {:?}: {}", entry.path(), e),
+            },
+            _ => {},
+        }
+    }
+
+    Ok(listing)
+}
+
+/// The form a path is stored in: as walked, without a leading `./`
+fn cache_key(path: &Path) -> String {
+    let key = path.strip_prefix(".").unwrap_or(path);
+    if key.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        key.to_string_lossy().to_string()
+    }
+}
+
+/// Whether the directory `dir` lies under `root`, both as stored by `cache_key`
+fn is_within(dir: &str, root: &str) -> bool {
+    if root == "." {
+        !Path::new(dir).is_absolute()
+    } else {
+        Path::new(dir).starts_with(root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_refresh_follows_changes() {
+        let root = std::env::temp_dir().join(format!("prlents-path-cache-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("sub").join("Report.pdf"), "a").unwrap();
+        let roots = vec![root.to_string_lossy().to_string()];
+
+        let mut cache = PathCache::default();
+        cache.refresh(&roots).unwrap();
+        let named = cache.paths_named(&roots, "report.PDF");
+        assert_eq!(named, vec![root.join("sub").join("Report.pdf").to_string_lossy().to_string()]);
+
+        // A refresh with nothing changed leaves the listing alone
+        cache.dirty = false;
+        cache.refresh(&roots).unwrap();
+        assert!(!cache.dirty);
+
+        fs::remove_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        cache.refresh(&roots).unwrap();
+        assert!(cache.paths_named(&roots, "report.pdf").is_empty());
+        assert_eq!(cache.files(&roots).len(), 1);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn test_ignore_rules() {
+        let root = std::env::temp_dir().join(format!("prlents-ignore-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for dir in ["target", "src/target", "keep", ".git"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+            fs::write(root.join(dir).join("a.txt"), "a").unwrap();
+        }
+        fs::write(root.join(IGNORE_FILE), "/target/\n").unwrap();
+        fs::write(root.join("keep").join(IGNORE_FILE), "*.txt\n").unwrap();
+        fs::write(root.join(".gitignore"), "src/\n").unwrap();
+        let roots = vec![root.to_string_lossy().to_string()];
+
+        let mut cache = PathCache::default();
+        cache.refresh(&roots).unwrap();
+        let named: Vec<String> = cache.paths_named(&roots, "a.txt").into_iter()
+            .map(|path| Path::new(&path).strip_prefix(&root).unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(named, vec!["src/target/a.txt"]);
+
+        cache.set_gitignore(true);
+        assert!(cache.paths_named(&roots, "a.txt").is_empty());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
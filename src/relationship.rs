@@ -1,9 +1,16 @@
 use std::error::Error;
-use std::collections::{HashSet};
+use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::Path;
+use std::os::unix::fs::MetadataExt;
 
-use crate::common::{TagType, EntsTag, TagsFile, FileData, save_tags_to_json};
-use crate::handle_file::{handle_file, find_filename_by_inode};
+use crate::common::{TagType, EntsTag, TagsFile, FileId, Assignment, format_timestamp};
+use crate::storage::is_database_file;
+use crate::handle_file::{
+    handle_file, follow_replaced_file, find_filenames_by_inodes, find_files_by_hashes, walk_files_with_inodes, hash_file, fuzzy_hash_file
+};
+use crate::fuzzy::fuzzy_compare;
+use crate::journal::{JournalRecord, TagChange};
 
 #[derive(Debug, Clone, Copy)]
 pub enum Operation {
@@ -34,27 +41,19 @@ pub fn assign_bidir_file_tag_rel(
     force: bool
 ) -> Result<(), Box<dyn Error>> {
 
-    // Look up the inode early to avoid borrowing conflicts
-    let file_inode = handle_file(file_name, tags_file)?;
-    let file_inode_str = file_inode.to_string();
+    // Look up the file id early to avoid borrowing conflicts
+    let file_id = handle_file(file_name, tags_file)?;
+    let file_inode_str = file_id.to_string();
 
-    // Resolve the actual tag name from aliases
-    let display_tag_name = match tags_file.aliases.get(tag) {
-        Some(actual_name) => actual_name.to_string(),
-        None => tag.to_string(),
-    };
-    
-    // Find the tag in the tags list
-    let foo_index = tags_file.tags.iter().position(|t| 
-        t.name == display_tag_name && is_visible_tag(t));
-    
-    let foo_index = match foo_index {
-        Some(index) => index,
-        None => {
-            println!("tag or alias does not exist: {}", tag);
+    // Resolve aliases and bare or path-qualified names to a single tag, known from here on by its path
+    let foo_index = match tags_file.resolve_tag(tag) {
+        Ok(index) => index,
+        Err(message) => {
+            println!("{}", message);
             return Ok(());
         }
     };
+    let display_tag_name = tags_file.tags[foo_index].path();
 
     let mut unassign_message = String::new();
 
@@ -70,8 +69,8 @@ pub fn assign_bidir_file_tag_rel(
 
                 TagType::Exclusive => {
                     let already_assigned_tags = single_inspect(tags_file, &file_inode_str)?;
-                    let (_, potential_children_tags) = collect_tags_recursively(tag, tags_file)?;
-                    let ancestry_set: HashSet<String> = foo.ancestry.iter().cloned().collect();
+                    let (_, potential_children_tags) = collect_tags_recursively(&display_tag_name, tags_file)?;
+                    let ancestry_set: HashSet<String> = foo.ancestor_paths().into_iter().collect();
 
                     let alt_common_elements: HashSet<_> = already_assigned_tags.intersection(&ancestry_set).cloned().collect();
 
@@ -79,8 +78,7 @@ pub fn assign_bidir_file_tag_rel(
                         // Check if any of the common ancestors are actually exclusive tags
                         for ancestor_name in &alt_common_elements {
                             // Find the ancestor tag and check its type
-                            if let Some(ancestor_tag) = tags_file.tags.iter().find(|t| 
-                                t.name == *ancestor_name && is_visible_tag(t)) {
+                            if let Some(ancestor_tag) = tags_file.find_tag(ancestor_name) {
                                 
                                 if ancestor_tag.tag_type == TagType::Exclusive {
                                     if !force {
@@ -89,7 +87,7 @@ pub fn assign_bidir_file_tag_rel(
                                         return Ok(());
                                     } else {
                                         //NEEDS CUSTOM MESSAGE
-                                        assign_bidir_file_tag_rel(file_name, ancestor_name, Operation::Remove, tags_file, false)?;
+                                        force_unassign(file_name, ancestor_name, &display_tag_name, tags_file)?;
                                         unassign_message = format!("and forcefully unassigned ancestor exclusive tag {}", ancestor_name);
                                     }
                                 }
@@ -115,7 +113,7 @@ pub fn assign_bidir_file_tag_rel(
                         } else {
                             for element in &elements_str {
                                 //NEEDS CUSTOM MESSAGE
-                                assign_bidir_file_tag_rel(file_name, element, Operation::Remove, tags_file, false)?;
+                                force_unassign(file_name, element, &display_tag_name, tags_file)?;
                             }
                             unassign_message = format!(
                                 "and forcefully unassigned {}{}",
@@ -128,15 +126,14 @@ pub fn assign_bidir_file_tag_rel(
 
                 TagType::Normal => {
                     let already_assigned_tags = single_inspect(tags_file, &file_inode_str)?;
-                    let ancestry_set: HashSet<String> = foo.ancestry.iter().cloned().collect();
+                    let ancestry_set: HashSet<String> = foo.ancestor_paths().into_iter().collect();
                     let common_elements: HashSet<_> = ancestry_set.intersection(&already_assigned_tags).cloned().collect();
                     
                     if !common_elements.is_empty() {
                         // Check if any of the common ancestors are actually exclusive tags
                         for ancestor_name in &common_elements {
                             // Find the ancestor tag and check its type
-                            if let Some(ancestor_tag) = tags_file.tags.iter().find(|t| 
-                                t.name == *ancestor_name && is_visible_tag(t)) {
+                            if let Some(ancestor_tag) = tags_file.find_tag(ancestor_name) {
                                 
                                 if ancestor_tag.tag_type == TagType::Exclusive {
                                     if !force {
@@ -146,7 +143,7 @@ pub fn assign_bidir_file_tag_rel(
                                     } else {
                                         //NEEDS CUSTOM MESSAGE
                                         unassign_message = format!("and forcefully unassigned ancestor exclusive tag {}", ancestor_name);
-                                        assign_bidir_file_tag_rel(file_name, ancestor_name, Operation::Remove, tags_file, false)?;
+                                        force_unassign(file_name, ancestor_name, &display_tag_name, tags_file)?;
                                     }
                                 }
                             }
@@ -157,11 +154,18 @@ pub fn assign_bidir_file_tag_rel(
             }
             
             // Add file to tag's files if not already present
-            let foo = &mut tags_file.tags[foo_index];
-            let files = foo.files.get_or_insert_with(Vec::new);
-
-            if !files.contains(&file_inode_str) {
-                files.push(file_inode_str);
+            if !tags_file.has_assignment(foo_index, &file_inode_str) {
+                let foo = &mut tags_file.tags[foo_index];
+                foo.files.get_or_insert_with(Vec::new).push(file_inode_str.clone());
+                foo.assigned.insert(file_inode_str.clone(), Assignment::now(!unassign_message.is_empty()));
+                tags_file.index.set_assigned(foo_index, &file_inode_str, true);
+                tags_file.journal.push(JournalRecord::Add(TagChange {
+                    tag: display_tag_name.clone(),
+                    file: file_inode_str,
+                    path: file_name.to_string(),
+                    forced: !unassign_message.is_empty(),
+                    cause: None,
+                }));
                 println!("assigned file, tag: \t{} \t{} {}", file_name, display_tag_name, unassign_message);
             } else {
                 println!("pre-exist file, tag: \t{} \t{}", file_name, display_tag_name);
@@ -174,6 +178,15 @@ pub fn assign_bidir_file_tag_rel(
             if let Some(files) = &mut foo.files {
                 if let Some(pos) = files.iter().position(|f| *f == file_inode_str) {
                     files.remove(pos);
+                    foo.assigned.remove(&file_inode_str);
+                    tags_file.index.set_assigned(foo_index, &file_inode_str, false);
+                    tags_file.journal.push(JournalRecord::Remove(TagChange {
+                        tag: display_tag_name.clone(),
+                        file: file_inode_str,
+                        path: file_name.to_string(),
+                        forced: false,
+                        cause: None,
+                    }));
                     println!("removed  file, tag: \t{} \t{}", file_name, tag); // there are meant to be two spaces there for text alignment 
                 } else {
                     println!("there is no correlation between file '{}' and tag '{}'", file_name, display_tag_name);
@@ -191,19 +204,25 @@ pub fn assign_bidir_file_tag_rel(
     Ok(())
 }
 
+/// Unassign `tag` as a side effect of force-assigning `cause`, journaling the removal as forced
+fn force_unassign(file_name: &str, tag: &str, cause: &str, tags_file: &mut TagsFile) -> Result<(), Box<dyn Error>> {
+    let recorded = tags_file.journal.len();
+    assign_bidir_file_tag_rel(file_name, tag, Operation::Remove, tags_file, false)?;
+
+    for record in &mut tags_file.journal[recorded..] {
+        if let JournalRecord::Remove(change) = record {
+            change.forced = true;
+            change.cause = Some(cause.to_string());
+        }
+    }
+    Ok(())
+}
+
 fn collect_tags_recursively(tag_name: &str, tags_file: &TagsFile) 
     -> Result<(HashSet<String>, HashSet<String>), Box<dyn Error>> {
     
-    // Resolve actual tag name from aliases
-    let display_tag_name = match tags_file.aliases.get(tag_name) {
-        Some(actual_name) => actual_name,
-        None => tag_name,
-    };
-    
-    // Find the tag in the tags list
-    let tag_obj = tags_file.tags.iter()
-        .find(|t| t.name == display_tag_name && is_visible_tag(t))
-        .ok_or_else(|| format!("tag '{}' is not in tags", tag_name))?;
+    // Find the tag in the tags list, resolving aliases and bare names
+    let tag_obj = &tags_file.tags[tags_file.resolve_tag(tag_name)?];
     
     let mut normal_and_duds_set = HashSet::new();
     let mut normal_tags_set = HashSet::new();
@@ -211,7 +230,7 @@ fn collect_tags_recursively(tag_name: &str, tags_file: &TagsFile)
     // Recursive helper function to collect tags
     fn edit_lists(
         tag_object: &EntsTag, 
-        all_tags: &[EntsTag],
+        tags_file: &TagsFile,
         normal_and_duds_set: &mut HashSet<String>, 
         normal_tags_set: &mut HashSet<String>
     ) {
@@ -224,30 +243,36 @@ fn collect_tags_recursively(tag_name: &str, tags_file: &TagsFile)
         }
         
         // Add to normal_and_duds_set
-        normal_and_duds_set.insert(tag_object.name.clone());
+        normal_and_duds_set.insert(tag_object.path());
         
         // Add to normal_tags_set if applicable
         if tag_object.tag_type == TagType::Normal || tag_object.tag_type == TagType::Exclusive {
-            normal_tags_set.insert(tag_object.name.clone());
+            normal_tags_set.insert(tag_object.path());
         }
         
         // Process children recursively
         for child_name in &tag_object.children {
-            if let Some(child_object) = all_tags.iter()
-                .find(|t| t.name == *child_name && is_visible_tag(t)) {
-                edit_lists(child_object, all_tags, normal_and_duds_set, normal_tags_set);
+            if let Some(child_object) = tags_file.find_tag(&tag_object.child_path(child_name)) {
+                edit_lists(child_object, tags_file, normal_and_duds_set, normal_tags_set);
             }
         }
     }
     
     // Start the recursive collection
-    edit_lists(tag_obj, &tags_file.tags, &mut normal_and_duds_set, &mut normal_tags_set);
+    edit_lists(tag_obj, tags_file, &mut normal_and_duds_set, &mut normal_tags_set);
     
     Ok((normal_and_duds_set, normal_tags_set))
 }
 
 
 pub fn filter_command(tags_file: &mut TagsFile, tags: &[String], explicit: bool) -> Result<Vec<String>, Box<dyn Error>> {
+    let unique_inodes = filter_inodes(tags_file, tags, explicit)?;
+    resolve_inodes(tags_file, &unique_inodes)
+}
+
+/// Collect the inodes of every file carrying any of `tags`
+/// Unless `explicit` is set, each tag is expanded to include all of its children
+pub fn filter_inodes(tags_file: &TagsFile, tags: &[String], explicit: bool) -> Result<HashSet<String>, Box<dyn Error>> {
     
     let mut all_normal_tags = HashSet::new();
     
@@ -256,119 +281,372 @@ pub fn filter_command(tags_file: &mut TagsFile, tags: &[String], explicit: bool)
             let (_, normal_tags_set) = collect_tags_recursively(tag, tags_file)?;
             all_normal_tags.extend(normal_tags_set);
         } else {
-            all_normal_tags.extend(tags.iter().cloned());
+            // Resolve aliases and bare names to the tag's path
+            let position = tags_file.resolve_tag(tag)?;
+            all_normal_tags.insert(tags_file.tags[position].path());
         }
     }
     
     let mut unique_inodes = HashSet::new();
 
     for tag_name in &all_normal_tags {
-        if let Some(tag_obj) = tags_file.tags.iter()
-            .find(|tag| tag.name == *tag_name && is_visible_tag(tag)) {
+        if let Some(tag_obj) = tags_file.find_tag(tag_name) {
             if let Some(files) = &tag_obj.files {
                 unique_inodes.extend(files.iter().cloned());
             }
         }
     }
+
+    Ok(unique_inodes)
+}
+
+/// Collect the inodes of files carrying any of `include` but none of `exclude`
+/// Excluded tags are always expanded to their children, even in explicit mode,
+/// so that excluding a parent tag also excludes everything filed beneath it
+pub fn difference_inodes(tags_file: &TagsFile, include: &[String], exclude: &[String], explicit: bool) -> Result<HashSet<String>, Box<dyn Error>> {
+    let included = filter_inodes(tags_file, include, explicit)?;
+    let excluded = filter_inodes(tags_file, exclude, false)?;
+    
+    Ok(included.difference(&excluded).cloned().collect())
+}
+
+/// Collect the inodes of every file assigned to at least one visible tag
+pub fn all_tagged_inodes(tags_file: &TagsFile) -> HashSet<String> {
+    tags_file.tagged_files().cloned().collect()
+}
+
+/// Every file with a visible tag whose recorded assignment satisfies `matches`
+pub fn assigned_inodes(tags_file: &TagsFile, matches: impl Fn(&Assignment) -> bool) -> HashSet<String> {
+    tags_file.tags.iter()
+        .filter(|tag| is_visible_tag(tag))
+        .flat_map(|tag| tag.assigned.iter())
+        .filter(|(_, assignment)| matches(assignment))
+        .map(|(file_id, _)| file_id.clone())
+        .collect()
+}
+
+/// List files under `roots` whose inode is not assigned to any visible tag
+/// If `extensions` is non-empty, only files with one of those extensions are listed;
+/// the files of the `database` in use are never listed
+pub fn untagged_command(tags_file: &mut TagsFile, roots: &[String], extensions: &[String], database: &Path) -> Result<Vec<String>, Box<dyn Error>> {
+    let tagged_inodes = all_tagged_inodes(tags_file);
+    let default_roots = [".".to_string()];
+    let roots = if roots.is_empty() { &default_roots[..] } else { roots };
+
+    let mut result = Vec::new();
+
+    for root in roots {
+        for (path, file_id) in walk_files_with_inodes(std::slice::from_ref(root), &mut tags_file.paths)? {
+            // The database and taxonomy files are never meant to be tagged
+            let file_name = Path::new(&path).file_name().map(|n| n.to_string_lossy().to_string());
+            if file_name.is_some_and(|name| is_database_file(&name, database)) {
+                continue;
+            }
+
+            if !extensions.is_empty() {
+                let extension = Path::new(&path).extension()
+                    .map(|e| e.to_string_lossy().to_lowercase())
+                    .unwrap_or_default();
+                if !extensions.iter().any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(&extension)) {
+                    continue;
+                }
+            }
+
+            if !tagged_inodes.contains(&file_id.to_string()) {
+                result.push(path);
+            }
+        }
+    }
+
+    result.sort();
+    result.dedup();
+
+    Ok(result)
+}
+
+/// Convert a set of inodes to sorted filenames, updating `last_known_name` for moved files
+/// Files that are no longer at their last known path are all looked up in a single walk,
+/// and any whose inode has vanished are then looked up by content in one more
+pub fn resolve_inodes(tags_file: &mut TagsFile, unique_inodes: &HashSet<String>) -> Result<Vec<String>, Box<dyn Error>> {
     
     // Track whether we need to save changes
     let mut needs_save = false;
     
     // Convert inodes to filenames and update last_known_name if needed
     let mut result: Vec<String> = Vec::new();
+    let mut unresolved: HashSet<FileId> = HashSet::new();
     
-    for inode_str in &unique_inodes {
-        // Convert string to u64 inode
-        if let Ok(inode) = inode_str.parse::<u64>() {
-
-            // if it finds the inode
-            if let Some((position, file_data)) = tags_file.files.iter().enumerate().find(|(_, file_data)| file_data.file_inode == inode) {
-                let last_known_name = &file_data.last_known_name;
-                // and if the last known file there
-                if Path::new(last_known_name).is_file() {
-                    result.push(last_known_name.clone());
-                // if the file name changed
+    for inode_str in unique_inodes {
+        // Convert string to a device and inode pair
+        if let Ok(file_id) = inode_str.parse::<FileId>() {
+
+            // if it finds the file id
+            if let Some(position) = tags_file.file_position(file_id) {
+                let last_known_name = tags_file.files[position].last_known_name.clone();
+                // and if the last known file there is still this file, possibly replaced under a new inode
+                if Path::new(&last_known_name).is_file() && follow_replaced_file(tags_file, file_id, &last_known_name).is_some() {
+                    result.push(last_known_name);
+                // if the file name changed, look it up with the others below
                 } else {
-                    // Look up current filename by inode using the file system
-                    match find_filename_by_inode(inode)? {
-                        Some(current_path) => {
-                            // File exists in our registry, check if name needs updating
-                            if tags_file.files[position].last_known_name != current_path {
-                                //println!("Updating file path: {} -> {}", tags_file.files[position].last_known_name, current_path);
-                                tags_file.files[position].last_known_name = current_path.clone();
-                                needs_save = true;
-                            }
-                            result.push(current_path);
-                        },
-                        None => {
-                            // File not found in filesystem - do not include it in results
-                            println!("Warning: File with inode {} not found in filesystem", inode);
-                            // We don't add it to the results since you don't want to show missing files
-                        }
-                    }
+                    unresolved.insert(file_id);
                 }
             }
         }
     }
+
+    if unresolved.is_empty() {
+        result.sort();
+        return Ok(result);
+    }
+
+    // Look up every moved file by inode in one pass over the file system
+    let found_paths = find_filenames_by_inodes(&unresolved, &tags_file.search_roots()?, &mut tags_file.paths)?;
+    for (file_id, current_path) in found_paths {
+        if let Some(position) = tags_file.file_position(file_id) {
+            if tags_file.files[position].last_known_name != current_path {
+                tags_file.files[position].last_known_name = current_path.clone();
+                needs_save = true;
+            }
+        }
+        unresolved.remove(&file_id);
+        result.push(current_path);
+    }
+
+    // The inodes that are gone, e.g. after a copy or restore; try to find the contents instead
+    let mut missing_hashes: HashMap<String, Vec<FileId>> = HashMap::new();
+    for file_id in &unresolved {
+        if let Some(hash) = tags_file.file_position(*file_id).and_then(|position| tags_file.files[position].sha1_hash.clone()) {
+            missing_hashes.entry(hash).or_default().push(*file_id);
+        }
+    }
+
+    if !missing_hashes.is_empty() {
+        let hashes: HashSet<String> = missing_hashes.keys().cloned().collect();
+        let tracked_ids: HashSet<FileId> = tags_file.files.iter().map(|f| f.id()).collect();
+
+        for (hash, matches) in find_files_by_hashes(&hashes, &tracked_ids, &tags_file.search_roots()?, &mut tags_file.paths)? {
+            let old_ids = &missing_hashes[&hash];
+
+            // Identical contents only identify a file when both sides are unique; otherwise leave it to the user
+            if old_ids.len() > 1 || matches.len() > 1 {
+                let paths: Vec<&str> = matches.iter().map(|(path, _)| path.as_str()).collect();
+                eprintln!("Warning: {} missing and {} untracked files have the same contents ({}); not relinked, tag the right one by path",
+                    old_ids.len(), matches.len(), paths.join(", "));
+                continue;
+            }
+
+            let old_id = old_ids[0];
+            let (current_path, new_id) = matches.into_iter().next().ok_or("hash match without a file")?;
+            let position = tags_file.file_position(old_id).ok_or("missing file record went missing")?;
+            eprintln!("relinked file: \t{} -> {}", tags_file.files[position].last_known_name, current_path);
+
+            tags_file.rekey_file(old_id, new_id);
+            if let Some(position) = tags_file.file_position(new_id) {
+                tags_file.files[position].last_known_name = current_path.clone();
+            }
+            unresolved.remove(&old_id);
+            needs_save = true;
+            result.push(current_path);
+        }
+    }
+
+    // File not found in filesystem - do not include it in results
+    for file_id in &unresolved {
+        println!("Warning: File with inode {} not found in filesystem", file_id.inode);
+    }
     
     result.sort();
     
-    // Save changes to tags.json if needed
+    // Have the caller save changes to the database if needed
     if needs_save {
-        save_tags_to_json(tags_file)?;
+        tags_file.dirty = true;
     }
     
     Ok(result)
 }
 
-// Modified to accept inode string directly instead of filename
-fn represent_single_inspect(tags_file: &TagsFile, file_inode_str: &str) -> Result<HashSet<String>, Box<dyn Error>> {
-    let mut return_set = HashSet::new();
-    
-    for tag in &tags_file.tags {
-        if is_visible_tag(tag) {
-            if let Some(files) = &tag.files {
-                if files.contains(&file_inode_str.to_string()) {
-                    if !tag.ancestry.is_empty() {
-                        let mut path_parts = tag.ancestry.clone();
-                        path_parts.push(tag.name.clone());
-                        let full_tag_path = path_parts.join("/");
-                        return_set.insert(full_tag_path);
-                    } else {
-                        return_set.insert(tag.name.clone());
-                    }
-                }
+/// Similarity a relink candidate needs by default before `--apply` moves tags to it
+pub const DEFAULT_MIN_SCORE: u32 = 50;
+
+/// How many points the best relink candidate must lead the next one by to be applied
+const MIN_LEAD: u32 = 10;
+
+/// Propose new locations for tagged files whose path and inode have both vanished
+/// Untracked files are ranked by similarity to each missing file's stored fuzzy hash
+/// With `apply`, the best candidate takes over the tags when it scores at least `min_score`
+/// and clearly beats the runner-up; the files of the `database` in use are never candidates
+pub fn relink_command(tags_file: &mut TagsFile, apply: bool, min_score: u32, database: &Path) -> Result<(), Box<dyn Error>> {
+    let walked = walk_files_with_inodes(&tags_file.search_roots()?, &mut tags_file.paths)?;
+    let present_ids: HashSet<FileId> = walked.iter().map(|(_, file_id)| *file_id).collect();
+    let tracked_ids: HashSet<FileId> = tags_file.files.iter().map(|f| f.id()).collect();
+    let tagged_inodes = all_tagged_inodes(tags_file);
+
+    let vanished: Vec<usize> = tags_file.files.iter().enumerate()
+        .filter(|(_, f)| tagged_inodes.contains(&f.id().to_string()))
+        .filter(|(_, f)| !Path::new(&f.last_known_name).is_file() && !present_ids.contains(&f.id()))
+        .map(|(position, _)| position)
+        .collect();
+
+    if vanished.is_empty() {
+        println!("no missing files to relink");
+        return Ok(());
+    }
+
+    // Hash each untracked file once, however many missing files it is compared against
+    let candidates: Vec<(String, FileId, String)> = walked.into_iter()
+        .filter(|(_, file_id)| !tracked_ids.contains(file_id))
+        .filter(|(path, _)| !Path::new(path).file_name().and_then(|n| n.to_str()).is_some_and(|name| is_database_file(name, database)))
+        .filter_map(|(path, file_id)| fuzzy_hash_file(Path::new(&path)).ok().map(|digest| (path, file_id, digest)))
+        .collect();
+
+    let mut claimed_ids = HashSet::new();
+    let mut needs_save = false;
+
+    for position in vanished {
+        let last_known_name = tags_file.files[position].last_known_name.clone();
+        let old_id = tags_file.files[position].id();
+
+        let digest = match &tags_file.files[position].fuzzy_hash {
+            Some(digest) => digest.clone(),
+            None => {
+                println!("no similarity hash recorded for: \t{}", last_known_name);
+                continue;
             }
+        };
+
+        let mut ranked: Vec<(u32, &String, FileId)> = candidates.iter()
+            .filter(|(_, file_id, _)| !claimed_ids.contains(file_id))
+            .map(|(path, file_id, candidate_digest)| (fuzzy_compare(&digest, candidate_digest), path, *file_id))
+            .filter(|(score, _, _)| *score > 0)
+            .collect();
+        ranked.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+
+        if ranked.is_empty() {
+            println!("no match found for: \t{}", last_known_name);
+            continue;
+        }
+
+        println!("candidates for: \t{}", last_known_name);
+        for (score, path, _) in ranked.iter().take(5) {
+            println!("\t{:>3}% \t{}", score, path);
+        }
+
+        if apply {
+            let (score, new_path, new_id) = ranked[0];
+            let runner_up = ranked.get(1).map_or(0, |(score, _, _)| *score);
+            if score < min_score {
+                println!("not relinked, best match is below {}%: \t{}", min_score, last_known_name);
+                continue;
+            }
+            if score < runner_up + MIN_LEAD {
+                println!("not relinked, no candidate clearly ahead: \t{}", last_known_name);
+                continue;
+            }
+
+            let parent_path = match Path::new(new_path).parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent,
+                _ => Path::new("."),
+            };
+            let parent_dir_inode = fs::metadata(parent_path)?.ino();
+
+            tags_file.rekey_file(old_id, new_id);
+            let position = tags_file.file_position(new_id).ok_or("relinked file record went missing")?;
+            let file_data = &mut tags_file.files[position];
+            file_data.last_known_name = new_path.clone();
+            file_data.parent_dir_inode = parent_dir_inode;
+            file_data.sha1_hash = hash_file(Path::new(new_path)).ok();
+            file_data.fuzzy_hash = fuzzy_hash_file(Path::new(new_path)).ok();
+
+            claimed_ids.insert(new_id);
+            needs_save = true;
+            println!("relinked file: \t{} -> {}", last_known_name, new_path);
         }
     }
-    
-    Ok(return_set)
+
+    if needs_save {
+        tags_file.dirty = true;
+    }
+
+    Ok(())
 }
 
-fn single_inspect(tags_file: &TagsFile, file_inode_str: &str) -> Result<HashSet<String>, Box<dyn Error>> {
-    let mut return_set = HashSet::new();
-    
-    for tag in &tags_file.tags {
-        if is_visible_tag(tag) {
-            if let Some(files) = &tag.files {
-                if files.contains(&file_inode_str.to_string()) {
-                    return_set.insert(tag.name.clone());  // Just the name, not the path
+/// Manage the directories a shared database searches: `add` and `rm` take absolute paths, `ls` lists them
+pub fn root_command(tags_file: &mut TagsFile, action: &str, paths: &[String]) -> Result<(), Box<dyn Error>> {
+    match action {
+        "add" => {
+            for path in paths {
+                let root = fs::canonicalize(path)
+                    .map_err(|e| format!("cannot register root {}: {}", path, e))?
+                    .to_string_lossy()
+                    .to_string();
+
+                if tags_file.roots.contains(&root) {
+                    println!("already a root: \t{}", root);
+                } else {
+                    println!("added root: \t{}", root);
+                    tags_file.roots.push(root);
+                    tags_file.dirty = true;
+                }
+            }
+        },
+        "rm" | "remove" => {
+            for path in paths {
+                // The directory may already be gone, so match the stored path too
+                let root = fs::canonicalize(path)
+                    .map(|p| p.to_string_lossy().to_string())
+                    .unwrap_or_else(|_| path.clone());
+
+                match tags_file.roots.iter().position(|r| *r == root || r == path) {
+                    Some(position) => {
+                        println!("removed root: \t{}", tags_file.roots.remove(position));
+                        tags_file.dirty = true;
+                    },
+                    None => println!("not a root: \t{}", path),
                 }
             }
+        },
+        "ls" | "list" => {
+            for root in &tags_file.roots {
+                println!("{}", root);
+            }
+        },
+        _ => println!("usage: prlents -g root <add|rm|ls> [paths]"),
+    }
+
+    Ok(())
+}
+
+// Modified to accept inode string directly instead of filename
+fn represent_single_inspect(tags_file: &TagsFile, file_inode_str: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    let mut return_set = HashSet::new();
+    
+    for tag in tags_file.tags_of_file(file_inode_str) {
+        let mut full_tag_path = tag.path();
+
+        // When and how it was assigned, if that was recorded
+        if let Some(assignment) = tag.assigned.get(file_inode_str) {
+            full_tag_path = format!("{}\t{}{}", full_tag_path, format_timestamp(assignment.timestamp),
+                if assignment.forced { " forced" } else { "" });
         }
+        return_set.insert(full_tag_path);
     }
     
     Ok(return_set)
 }
 
+fn single_inspect(tags_file: &TagsFile, file_inode_str: &str) -> Result<HashSet<String>, Box<dyn Error>> {
+    // Just the paths, without when they were assigned
+    Ok(tags_file.tags_of_file(file_inode_str).map(|tag| tag.path()).collect())
+}
+
 pub fn represent_inspect(tags_file: &mut TagsFile, files: &[String], quiet: bool) -> Result<(), Box<dyn Error>> {
     let multi_display = files.len() > 1;
     let tab_container = if multi_display { "\t" } else { "" };
 
     for (_count, file) in files.iter().enumerate() {
-        // Look up the inode first
-        let file_inode = handle_file(file, tags_file)?;
-        let file_inode_str = file_inode.to_string();
+        // Look up the file id first
+        let file_id = handle_file(file, tags_file)?;
+        let file_inode_str = file_id.to_string();
         
         // Then call single_inspect with the inode string
         let elements_set = represent_single_inspect(tags_file, &file_inode_str)?;
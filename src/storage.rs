@@ -0,0 +1,397 @@
+#[cfg(test)]
+use std::cell::RefCell;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use fs2::FileExt;
+
+use crate::common::TagsFile;
+use crate::journal::Journal;
+use crate::migrate::migrate;
+use crate::path_cache::PathCache;
+
+/// A modification applied to a loaded database by `TagStore::update`
+pub type Change<'a> = dyn FnMut(&mut TagsFile) -> Result<(), Box<dyn Error>> + 'a;
+
+/// A place the tag database can be loaded from and saved to
+/// Every read and write of the database goes through one of these
+pub trait TagStore {
+    /// Read the database as stored, or an empty one if it does not exist yet
+    fn read(&self) -> Result<TagsFile, Box<dyn Error>>;
+
+    /// Read the database, upgrade it to the current layout and index it
+    /// An upgraded database is marked dirty so the next save writes the new layout
+    fn load(&self) -> Result<TagsFile, Box<dyn Error>> {
+        let mut tags_file = self.read()?;
+        let from_version = tags_file.version;
+
+        if !migrate(&mut tags_file)?.is_empty() {
+            println!("migrated {} from version {} to {}", self.describe(), from_version, tags_file.version);
+            tags_file.dirty = true;
+        }
+        tags_file.build_index();
+        Ok(tags_file)
+    }
+
+    /// Replace the stored database with `tags_file`
+    fn save(&self, tags_file: &TagsFile) -> Result<(), Box<dyn Error>>;
+
+    /// Whether a database has been saved here before
+    fn exists(&self) -> bool;
+
+    /// Human readable location, used in messages
+    fn describe(&self) -> String;
+
+    /// Take exclusive access to the database for a whole read-modify-write cycle
+    /// Waits up to `timeout` for other processes to finish; stores without concurrent users need not lock
+    fn lock(&self, _timeout: Duration) -> Result<StoreLock, Box<dyn Error>> {
+        Ok(StoreLock { _file: None })
+    }
+
+    /// The journal recording changes to this database, if it keeps one
+    fn journal(&self) -> Option<Journal> {
+        None
+    }
+
+    /// The cached listing of the trees this database covers, if it keeps one
+    fn path_cache(&self) -> Option<PathCache> {
+        None
+    }
+
+    /// Load the database, apply `change`, and save it back only if it was marked dirty
+    /// The changes it recorded are then appended to the journal as one transaction,
+    /// and the path cache is saved if a lookup refreshed it
+    fn update(&self, change: &mut Change) -> Result<(), Box<dyn Error>> {
+        let mut tags_file = self.load()?;
+        if let Some(paths) = self.path_cache() {
+            tags_file.paths = paths;
+        }
+        change(&mut tags_file)?;
+        tags_file.paths.save()?;
+
+        if tags_file.dirty {
+            self.save(&tags_file)?;
+            if let Some(journal) = self.journal() {
+                journal.append(&tags_file.journal)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Held while a process has exclusive access to a database; dropping it releases the lock
+pub struct StoreLock {
+    _file: Option<fs::File>,
+}
+
+/// Lock a database file through an advisory lock on `<path>.lock`
+/// The database itself is replaced by rename on every save, so a lock held on it would
+/// be left behind on the old file; the sidecar lock file is never replaced
+fn lock_database_file(path: &Path, timeout: Duration) -> Result<StoreLock, Box<dyn Error>> {
+    let mut lock_path = path.as_os_str().to_owned();
+    lock_path.push(".lock");
+    let lock_path = PathBuf::from(lock_path);
+
+    let lock_file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)?;
+
+    let started = Instant::now();
+    loop {
+        match lock_file.try_lock_exclusive() {
+            Ok(()) => return Ok(StoreLock { _file: Some(lock_file) }),
+            Err(e) if e.kind() == fs2::lock_contended_error().kind() => {
+                if started.elapsed() >= timeout {
+                    return Err(format!(
+                        "could not lock {} within {} seconds; another prlents command is still using it",
+                        path.display(), timeout.as_secs()
+                    ).into());
+                }
+                thread::sleep(Duration::from_millis(50));
+            },
+            Err(e) => return Err(format!("could not lock {}: {}", lock_path.display(), e).into()),
+        }
+    }
+}
+
+/// Database file names looked for when walking up from the current directory, in order of preference
+pub const DATABASE_NAMES: [&str; 2] = ["tags.json", "tags.bson"];
+
+/// Whether a file name belongs to prlents itself rather than the tagged tree
+/// This covers both default databases and the active `database`, with the backup, lock, temporary
+/// and corrupt copies, journal and path cache that all start with a database's name, as well as
+/// the taxonomy and the ignore file
+pub fn is_database_file(file_name: &str, database: &Path) -> bool {
+    let name = file_name.trim_start_matches('.');
+    let database_name = database.file_name().map(|n| n.to_string_lossy()).unwrap_or_default();
+    let database_name = database_name.trim_start_matches('.');
+    name == "tags.ents" || name == "entsignore"
+        || DATABASE_NAMES.iter().any(|default| name.starts_with(default))
+        || (!database_name.is_empty() && name.starts_with(database_name))
+}
+
+/// The database used when none is named: tags.json, or tags.bson if only that exists
+pub fn default_database_path() -> PathBuf {
+    if !Path::new("tags.json").exists() && Path::new("tags.bson").exists() {
+        PathBuf::from("tags.bson")
+    } else {
+        PathBuf::from("tags.json")
+    }
+}
+
+/// The user-level database shared across roots, `$XDG_DATA_HOME/prlents/tags.json`
+/// Falls back to `~/.local/share` when `XDG_DATA_HOME` is unset; the directory is created on first use
+pub fn global_database_path() -> Result<PathBuf, Box<dyn Error>> {
+    let data_home = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => {
+            let home = env::var_os("HOME").ok_or("cannot find the global database: neither XDG_DATA_HOME nor HOME is set")?;
+            PathBuf::from(home).join(".local").join("share")
+        }
+    };
+
+    let directory = data_home.join("prlents");
+    fs::create_dir_all(&directory)?;
+    Ok(directory.join("tags.json"))
+}
+
+/// The database file a command uses: `path` if one was given, otherwise the default one
+pub fn database_path(path: Option<&str>) -> PathBuf {
+    path.map(PathBuf::from).unwrap_or_else(default_database_path)
+}
+
+/// Open the store for a database file, picking the backend from its extension
+/// With no path, the default database in the current directory is used
+pub fn open_store(path: Option<&str>) -> Box<dyn TagStore> {
+    let path = database_path(path);
+
+    match path.extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("bson") => Box::new(BsonStore { path }),
+        _ => Box::new(JsonStore { path }),
+    }
+}
+
+/// Read a database file, handing back None if it does not exist
+fn read_database_file(path: &Path) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    match fs::read(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into())
+    }
+}
+
+/// The previous version of a database, kept by `write_atomically`
+fn backup_path(path: &Path) -> PathBuf {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    PathBuf::from(backup)
+}
+
+/// Turns the raw bytes of a database file into a `TagsFile`
+type Decoder = fn(&[u8]) -> Result<TagsFile, Box<dyn Error>>;
+
+/// Load and decode a database file, falling back to its backup if it is missing or cannot be decoded
+/// A recovered database is written back in place before anything else runs, so a command that
+/// fails afterwards cannot leave the tree without a database; an unreadable file is first
+/// copied aside to `<path>.corrupt`
+fn load_database_file(path: &Path, decode: Decoder) -> Result<TagsFile, Box<dyn Error>> {
+    let backup = backup_path(path);
+    let error = match read_database_file(path)? {
+        Some(content) => match decode(&content) {
+            Ok(tags_file) => return Ok(tags_file),
+            Err(e) => Some(e),
+        },
+        None if backup.exists() => None,
+        None => {
+            println!("Error: {} not found. Run 'prlents process tags.ents' to create it.", path.display());
+            return Ok(TagsFile::default());
+        }
+    };
+
+    let recovered = match read_database_file(&backup)? {
+        Some(backup_content) => decode(&backup_content).ok().map(|tags_file| (tags_file, backup_content)),
+        None => None,
+    };
+
+    match (recovered, error) {
+        (Some((tags_file, backup_content)), Some(error)) => {
+            let mut corrupt = path.as_os_str().to_owned();
+            corrupt.push(".corrupt");
+            fs::copy(path, &corrupt)?;
+            write_atomically(path, &backup_content, false)?;
+
+            eprintln!("Warning: {} could not be read ({}); restored it from {} and copied the damaged file to {}",
+                path.display(), error, backup.display(), Path::new(&corrupt).display());
+            Ok(tags_file)
+        },
+        (Some((tags_file, backup_content)), None) => {
+            write_atomically(path, &backup_content, false)?;
+            eprintln!("Warning: {} was missing; restored it from {}", path.display(), backup.display());
+            Ok(tags_file)
+        },
+        (None, Some(error)) => Err(format!("{} could not be read and no usable backup exists: {}", path.display(), error).into()),
+        (None, None) => Err(format!("{} is missing and its backup {} could not be read", path.display(), backup.display()).into()),
+    }
+}
+
+/// Replace `path` with `content` without ever leaving a partially written file behind
+/// The content is written and synced to a temporary file next to `path`, the current file
+/// becomes `<path>.bak` when `keep_backup` is set, and the temporary file is then renamed into place
+pub fn write_atomically(path: &Path, content: &[u8], keep_backup: bool) -> Result<(), Box<dyn Error>> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let file_name = path.file_name()
+        .ok_or_else(|| format!("invalid database path {}", path.display()))?
+        .to_string_lossy();
+    let temp_path = directory.join(format!(".{}.tmp-{}", file_name, std::process::id()));
+
+    let write_result = (|| -> Result<(), Box<dyn Error>> {
+        let mut temp_file = fs::File::create(&temp_path)?;
+        temp_file.write_all(content)?;
+        temp_file.sync_all()?;
+        Ok(())
+    })();
+    if let Err(e) = write_result {
+        let _ = fs::remove_file(&temp_path);
+        return Err(e);
+    }
+
+    // Rotate the current version into the backup; a hard link avoids copying the data
+    if keep_backup && path.exists() {
+        let backup = backup_path(path);
+        let _ = fs::remove_file(&backup);
+        if fs::hard_link(path, &backup).is_err() {
+            fs::copy(path, &backup)?;
+        }
+    }
+
+    fs::rename(&temp_path, path)?;
+
+    // Make the rename itself durable
+    if let Ok(dir) = fs::File::open(directory) {
+        let _ = dir.sync_all();
+    }
+
+    Ok(())
+}
+
+/// The plain JSON document database, stored as pretty-printed `tags.json`
+pub struct JsonStore {
+    pub path: PathBuf,
+}
+
+impl TagStore for JsonStore {
+    fn read(&self) -> Result<TagsFile, Box<dyn Error>> {
+        load_database_file(&self.path, |content| Ok(serde_json::from_slice(content)?))
+    }
+
+    fn save(&self, tags_file: &TagsFile) -> Result<(), Box<dyn Error>> {
+        let json_content = serde_json::to_string_pretty(tags_file)?;
+        write_atomically(&self.path, (json_content + "\n").as_bytes(), true)
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn lock(&self, timeout: Duration) -> Result<StoreLock, Box<dyn Error>> {
+        lock_database_file(&self.path, timeout)
+    }
+
+    fn journal(&self) -> Option<Journal> {
+        Some(Journal::beside(&self.path))
+    }
+
+    fn path_cache(&self) -> Option<PathCache> {
+        Some(PathCache::beside(&self.path))
+    }
+}
+
+/// The same schema encoded as a single BSON document
+pub struct BsonStore {
+    pub path: PathBuf,
+}
+
+impl TagStore for BsonStore {
+    fn read(&self) -> Result<TagsFile, Box<dyn Error>> {
+        load_database_file(&self.path, |content| Ok(bson::from_slice(content)?))
+    }
+
+    fn save(&self, tags_file: &TagsFile) -> Result<(), Box<dyn Error>> {
+        write_atomically(&self.path, &bson::to_vec(tags_file)?, true)
+    }
+
+    fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    fn describe(&self) -> String {
+        self.path.display().to_string()
+    }
+
+    fn lock(&self, timeout: Duration) -> Result<StoreLock, Box<dyn Error>> {
+        lock_database_file(&self.path, timeout)
+    }
+
+    fn journal(&self) -> Option<Journal> {
+        Some(Journal::beside(&self.path))
+    }
+
+    fn path_cache(&self) -> Option<PathCache> {
+        Some(PathCache::beside(&self.path))
+    }
+}
+
+/// A database that only lives in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryStore {
+    pub tags_file: RefCell<Option<TagsFile>>,
+}
+
+#[cfg(test)]
+impl TagStore for MemoryStore {
+    fn read(&self) -> Result<TagsFile, Box<dyn Error>> {
+        Ok(self.tags_file.borrow().clone().unwrap_or_default())
+    }
+
+    fn save(&self, tags_file: &TagsFile) -> Result<(), Box<dyn Error>> {
+        let mut saved = tags_file.clone();
+        saved.dirty = false;
+        *self.tags_file.borrow_mut() = Some(saved);
+        Ok(())
+    }
+
+    fn exists(&self) -> bool {
+        self.tags_file.borrow().is_some()
+    }
+
+    fn describe(&self) -> String {
+        "in-memory database".to_string()
+    }
+}
+
+/// Copy the database from one store into another, e.g. from JSON to BSON
+pub fn convert_store(from: &dyn TagStore, to: &dyn TagStore) -> Result<(), Box<dyn Error>> {
+    if !from.exists() {
+        return Err(format!("cannot convert missing database {}", from.describe()).into());
+    }
+
+    let tags_file = from.load()?;
+    to.save(&tags_file)?;
+
+    println!("converted {} to {}", from.describe(), to.describe());
+    Ok(())
+}
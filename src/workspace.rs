@@ -0,0 +1,141 @@
+use std::env;
+use std::error::Error;
+use std::path::{Component, Path, PathBuf};
+
+use crate::storage::DATABASE_NAMES;
+
+/// The tagged tree a command runs against
+/// All stored paths are relative to `root`; `cwd_in_root` is where the user actually is,
+/// so arguments and output can be translated to and from what they typed
+/// A global workspace stores absolute paths instead, since its files live under many roots
+pub struct Workspace {
+    pub root: PathBuf,
+    pub cwd_in_root: PathBuf,
+    pub database: Option<String>,
+    pub global: bool,
+}
+
+impl Workspace {
+    /// Find the nearest directory at or above `cwd` that holds a tag database, the way git finds `.git`
+    /// Falls back to `cwd` itself when no database exists anywhere above it
+    pub fn discover(cwd: &Path) -> Workspace {
+        for dir in cwd.ancestors() {
+            if let Some(name) = DATABASE_NAMES.iter().find(|name| dir.join(name).is_file()) {
+                return Workspace {
+                    root: dir.to_path_buf(),
+                    cwd_in_root: cwd.strip_prefix(dir).unwrap_or(Path::new("")).to_path_buf(),
+                    database: Some(name.to_string()),
+                    global: false,
+                };
+            }
+        }
+
+        Workspace {
+            root: cwd.to_path_buf(),
+            cwd_in_root: PathBuf::new(),
+            database: None,
+            global: false,
+        }
+    }
+
+    /// Use the user-level database at `database`, staying in `cwd`
+    pub fn global(database: &Path, cwd: &Path) -> Workspace {
+        Workspace {
+            root: cwd.to_path_buf(),
+            cwd_in_root: PathBuf::new(),
+            database: Some(database.to_string_lossy().to_string()),
+            global: true,
+        }
+    }
+
+    /// Use an explicitly named database, rooting the workspace at the directory that holds it
+    pub fn from_database(database: &str, cwd: &Path) -> Result<Workspace, Box<dyn Error>> {
+        let database_path = normalize(&cwd.join(database));
+        let root = database_path.parent().map(Path::to_path_buf).unwrap_or_else(|| cwd.to_path_buf());
+        let file_name = database_path.file_name()
+            .ok_or_else(|| format!("invalid database path {}", database))?
+            .to_string_lossy()
+            .to_string();
+
+        let cwd_in_root = match cwd.strip_prefix(&root) {
+            Ok(rel) => rel.to_path_buf(),
+            // Working outside the tree; relative arguments are then resolved from `cwd`
+            Err(_) => relative_path(&root, cwd),
+        };
+
+        Ok(Workspace { root, cwd_in_root, database: Some(file_name), global: false })
+    }
+
+    /// Make the workspace root the current directory so stored relative paths resolve
+    pub fn enter(&self) -> Result<(), Box<dyn Error>> {
+        env::set_current_dir(&self.root)
+            .map_err(|e| format!("cannot enter {}: {}", self.root.display(), e).into())
+    }
+
+    /// Translate a path typed by the user into one relative to the workspace root
+    /// Paths that lead outside the root, and every path in a global workspace, are returned as absolute paths
+    pub fn to_root_relative(&self, path: &str) -> String {
+        let joined = if Path::new(path).is_absolute() {
+            PathBuf::from(path)
+        } else {
+            self.root.join(&self.cwd_in_root).join(path)
+        };
+        let joined = normalize(&joined);
+
+        if self.global {
+            return joined.to_string_lossy().to_string();
+        }
+
+        match joined.strip_prefix(&self.root) {
+            Ok(rel) if rel.as_os_str().is_empty() => ".".to_string(),
+            Ok(rel) => rel.to_string_lossy().to_string(),
+            Err(_) => joined.to_string_lossy().to_string(),
+        }
+    }
+
+    /// Translate a stored root-relative path into one relative to where the user is
+    pub fn to_display(&self, path: &str) -> String {
+        let path = Path::new(path.strip_prefix("./").unwrap_or(path));
+        if path.is_absolute() || self.cwd_in_root.as_os_str().is_empty() {
+            return path.to_string_lossy().to_string();
+        }
+
+        relative_path(&self.cwd_in_root, path).to_string_lossy().to_string()
+    }
+}
+
+/// Resolve `.` and `..` components without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {},
+            Component::ParentDir => {
+                if !result.pop() {
+                    result.push("..");
+                }
+            },
+            other => result.push(other.as_os_str()),
+        }
+    }
+    result
+}
+
+/// The path that leads from directory `from` to `to`, both relative to the same base
+fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = normalize(from);
+    let to = normalize(to);
+    let from_parts: Vec<_> = from.components().collect();
+    let to_parts: Vec<_> = to.components().collect();
+
+    let common = from_parts.iter().zip(&to_parts).take_while(|(a, b)| a == b).count();
+
+    let mut result = PathBuf::new();
+    for _ in common..from_parts.len() {
+        result.push("..");
+    }
+    for part in &to_parts[common..] {
+        result.push(part.as_os_str());
+    }
+    result
+}